@@ -0,0 +1,612 @@
+mod fifo;
+mod filter;
+mod leveled;
+mod tiered;
+
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+pub use fifo::{FifoCompactionOptions, FifoCompactionStrategy};
+pub use filter::{CompactionFilter, CompactionFilterDecision};
+pub use leveled::{LeveledCompactionOptions, LeveledCompactionStrategy};
+pub use tiered::{TieredCompactionOptions, TieredCompactionStrategy};
+
+use crate::iterators::merge_iterator::MergeIterator;
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::{range_overlap, LsmStorageCore, LsmStorageInner, NUM_LEVELS};
+use crate::manifest::ManifestRecord;
+use crate::rate_limiter::IoPriority;
+use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+
+/// A compaction strategy decides what to compact by looking at the shape of the LSM tree.
+pub trait CompactionStrategy: Send + Sync + std::fmt::Debug {
+    /// Pick the next compaction to run on `snapshot`, if any. The controller keeps running the
+    /// tasks picked by the strategy until it returns `None`.
+    fn generate_compaction_task(&self, snapshot: &LsmStorageInner) -> Option<CompactionTask>;
+
+    /// Estimate how many bytes have to be compacted until the strategy has nothing to do. Writes
+    /// are slowed down or stopped when this grows too large. By default, the size of the input
+    /// SSTs of the next task.
+    fn pending_compaction_bytes(&self, snapshot: &LsmStorageInner) -> u64 {
+        self.generate_compaction_task(snapshot).map_or(0, |task| {
+            task.input_sst_ids()
+                .filter_map(|id| snapshot.sst_by_id(id))
+                .map(|sst| sst.table_size())
+                .sum()
+        })
+    }
+}
+
+/// Never compacts, L0 grows forever.
+#[derive(Debug, Default)]
+pub struct NoCompactionStrategy;
+
+impl CompactionStrategy for NoCompactionStrategy {
+    fn generate_compaction_task(&self, _snapshot: &LsmStorageInner) -> Option<CompactionTask> {
+        None
+    }
+}
+
+/// Options of the compaction strategy.
+#[derive(Debug, Clone)]
+pub enum CompactionOptions {
+    Leveled(LeveledCompactionOptions),
+    Tiered(TieredCompactionOptions),
+    Fifo(FifoCompactionOptions),
+    NoCompaction,
+    /// A strategy implemented outside of the engine.
+    Custom(Arc<dyn CompactionStrategy>),
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        CompactionOptions::Leveled(LeveledCompactionOptions::default())
+    }
+}
+
+/// Runs the compactions picked by the compaction strategy, and applies their results to the LSM
+/// tree.
+pub struct CompactionController {
+    strategy: Arc<dyn CompactionStrategy>,
+}
+
+impl CompactionController {
+    pub fn new(options: &CompactionOptions) -> Self {
+        let strategy: Arc<dyn CompactionStrategy> = match options {
+            CompactionOptions::Leveled(options) => {
+                Arc::new(LeveledCompactionStrategy::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                Arc::new(TieredCompactionStrategy::new(options.clone()))
+            }
+            CompactionOptions::Fifo(options) => {
+                Arc::new(FifoCompactionStrategy::new(options.clone()))
+            }
+            CompactionOptions::NoCompaction => Arc::new(NoCompactionStrategy),
+            CompactionOptions::Custom(strategy) => strategy.clone(),
+        };
+        Self { strategy }
+    }
+
+    /// Pick the next compaction to run, if any. Fails if the strategy picks an invalid task.
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageInner,
+    ) -> Result<Option<CompactionTask>> {
+        let Some(task) = self.strategy.generate_compaction_task(snapshot) else {
+            return Ok(None);
+        };
+        if !(1..=NUM_LEVELS).contains(&task.output_level) {
+            bail!("invalid compaction task: no level {}", task.output_level);
+        }
+        if task.inputs.iter().all(Vec::is_empty) {
+            bail!("invalid compaction task: no input SSTs");
+        }
+        let mut ids = HashSet::new();
+        for id in task.input_sst_ids() {
+            if snapshot.sst_by_id(id).is_none() || !ids.insert(id) {
+                bail!(
+                    "invalid compaction task: SST {} is missing or duplicated",
+                    id
+                );
+            }
+        }
+        Ok(Some(task))
+    }
+
+    /// Estimate how many bytes have to be compacted until the strategy has nothing to do.
+    pub fn pending_compaction_bytes(&self, snapshot: &LsmStorageInner) -> u64 {
+        self.strategy.pending_compaction_bytes(snapshot)
+    }
+
+    /// Replace the input SSTs of a finished compaction with its output.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageInner,
+        task: &CompactionTask,
+        output: Vec<Arc<SsTable>>,
+    ) -> LsmStorageInner {
+        let mut snapshot = snapshot.clone();
+        let removed = task.input_sst_ids().collect::<HashSet<_>>();
+        snapshot
+            .l0_sstables
+            .retain(|sst| !removed.contains(&sst.sst_id()));
+        for level in &mut snapshot.levels {
+            level.retain(|sst| !removed.contains(&sst.sst_id()));
+        }
+        let level = &mut snapshot.levels[task.output_level - 1];
+        level.extend(output);
+        level.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        snapshot
+    }
+}
+
+/// A compaction merges the SSTs of some sorted runs into new SSTs in `output_level`, or just
+/// deletes them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionTask {
+    /// Ids of the input SSTs, grouped by sorted run, from the newest run to the oldest. Each L0
+    /// SST is a sorted run by itself.
+    pub inputs: Vec<Vec<usize>>,
+    /// The level that the new SSTs are added to, starting from 1.
+    pub output_level: usize,
+    /// Delete the input SSTs without writing anything, e.g. to drop data that is too old.
+    pub delete_inputs: bool,
+}
+
+impl CompactionTask {
+    /// Ids of all input SSTs.
+    pub fn input_sst_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.inputs.iter().flatten().copied()
+    }
+}
+
+/// Ids of the SSTs in `level` whose key range overlaps with `[first_key, last_key]`.
+pub fn overlapping_ssts(level: &[Arc<SsTable>], first_key: &[u8], last_key: &[u8]) -> Vec<usize> {
+    level
+        .iter()
+        .filter(|sst| &sst.first_key()[..] <= last_key && &sst.last_key()[..] >= first_key)
+        .map(|sst| sst.sst_id())
+        .collect()
+}
+
+/// Build a task that moves all SSTs overlapping with `[lower, upper]` to the bottom level. The
+/// range is widened until no SST left out of the task overlaps with the task's output, so that
+/// the bottom level stays sorted and no newer version of a key stays above an older one.
+fn compact_range_task(
+    snapshot: &LsmStorageInner,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+) -> Option<CompactionTask> {
+    let all_ssts = || {
+        snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flatten())
+    };
+    let mut selected = all_ssts()
+        .filter(|sst| range_overlap(lower, upper, sst.first_key(), sst.last_key()))
+        .map(|sst| sst.sst_id())
+        .collect::<HashSet<_>>();
+    if selected.is_empty() {
+        return None;
+    }
+    loop {
+        let first_key = all_ssts()
+            .filter(|sst| selected.contains(&sst.sst_id()))
+            .map(|sst| sst.first_key())
+            .min()?;
+        let last_key = all_ssts()
+            .filter(|sst| selected.contains(&sst.sst_id()))
+            .map(|sst| sst.last_key())
+            .max()?;
+        let mut widened = HashSet::new();
+        for ids in std::iter::once(&snapshot.l0_sstables)
+            .chain(snapshot.levels.iter())
+            .map(|level| overlapping_ssts(level, first_key, last_key))
+        {
+            widened.extend(ids);
+        }
+        if widened == selected {
+            break;
+        }
+        selected = widened;
+    }
+
+    let mut inputs = snapshot
+        .l0_sstables
+        .iter()
+        .rev()
+        .filter(|sst| selected.contains(&sst.sst_id()))
+        .map(|sst| vec![sst.sst_id()])
+        .collect::<Vec<_>>();
+    for level in &snapshot.levels {
+        let run = level
+            .iter()
+            .map(|sst| sst.sst_id())
+            .filter(|id| selected.contains(id))
+            .collect::<Vec<_>>();
+        if !run.is_empty() {
+            inputs.push(run);
+        }
+    }
+    Some(CompactionTask {
+        inputs,
+        output_level: NUM_LEVELS,
+        delete_inputs: false,
+    })
+}
+
+/// Whether the output of `task` goes to the bottommost level, i.e. no SST below the output level
+/// overlaps with the input SSTs. Reads never look past such output for a key in its range, so
+/// tombstones in it can be dropped.
+fn is_bottommost(snapshot: &LsmStorageInner, task: &CompactionTask) -> bool {
+    let inputs = task
+        .input_sst_ids()
+        .filter_map(|id| snapshot.sst_by_id(id))
+        .collect::<Vec<_>>();
+    let (Some(first_key), Some(last_key)) = (
+        inputs.iter().map(|sst| sst.first_key()).min(),
+        inputs.iter().map(|sst| sst.last_key()).max(),
+    ) else {
+        return true;
+    };
+    let input_ids = task.input_sst_ids().collect::<HashSet<_>>();
+    snapshot.levels[task.output_level..].iter().all(|level| {
+        overlapping_ssts(level, first_key, last_key)
+            .iter()
+            .all(|id| input_ids.contains(id))
+    })
+}
+
+/// Keys splitting the input SSTs into at most `max_subcompactions` ranges of about the same number
+/// of blocks. The first keys of the blocks are the candidates.
+fn subcompaction_boundaries(ssts: &[Arc<SsTable>], max_subcompactions: usize) -> Vec<Bytes> {
+    if max_subcompactions <= 1 {
+        return Vec::new();
+    }
+    let mut keys = ssts
+        .iter()
+        .flat_map(|sst| sst.block_metas().iter().map(|meta| meta.first_key.clone()))
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    // Nothing is before the smallest key, so it cannot split anything.
+    if !keys.is_empty() {
+        keys.remove(0);
+    }
+    let num_ranges = max_subcompactions.min(keys.len() + 1);
+    let mut boundaries = (1..num_ranges)
+        .map(|i| keys[i * keys.len() / num_ranges].clone())
+        .collect::<Vec<_>>();
+    boundaries.dedup();
+    boundaries
+}
+
+impl LsmStorageInner {
+    /// Find an SST in L0 or any level by id.
+    fn sst_by_id(&self, id: usize) -> Option<&Arc<SsTable>> {
+        self.l0_sstables
+            .iter()
+            .chain(self.levels.iter().flatten())
+            .find(|sst| sst.sst_id() == id)
+    }
+}
+
+impl LsmStorageCore {
+    /// Run compactions picked by the compaction strategy until there is nothing to compact.
+    pub(crate) fn trigger_compaction(&self) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.lock();
+        loop {
+            let snapshot = {
+                let guard = self.inner.read();
+                Arc::clone(&guard)
+            };
+            let Some(task) = self
+                .compaction_controller
+                .generate_compaction_task(&snapshot)?
+            else {
+                return Ok(());
+            };
+            self.run_compaction(&snapshot, &task)?;
+            self.update_write_stall();
+        }
+    }
+
+    /// Compact all SSTs overlapping with `[lower, upper]` into the bottom level. The memtables are
+    /// flushed first if they contain keys in the range.
+    pub(crate) fn compact_range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<()> {
+        self.check_not_closed()?;
+        if let CompactionOptions::Fifo(_) = self.options.compaction_options {
+            // FIFO compaction only drops SSTs from L0, so SSTs moved to the bottom level would be
+            // kept forever.
+            bail!("compact_range is not supported with FIFO compaction");
+        }
+        let needs_flush = {
+            let guard = self.inner.read();
+            std::iter::once(&guard.memtable)
+                .chain(guard.imm_memtables.iter())
+                .any(|memtable| memtable.scan(lower, upper).is_valid())
+        };
+        if needs_flush {
+            self.force_flush()?;
+        }
+
+        let _compaction_lock = self.compaction_lock.lock();
+        let snapshot = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        let Some(task) = compact_range_task(&snapshot, lower, upper) else {
+            return Ok(());
+        };
+        self.run_compaction(&snapshot, &task)?;
+        self.update_write_stall();
+        Ok(())
+    }
+
+    /// Run `task` and replace its input SSTs with the output in the LSM tree. The input SSTs are
+    /// removed from disk afterwards, unless they are moved to the output level as they are.
+    fn run_compaction(&self, snapshot: &LsmStorageInner, task: &CompactionTask) -> Result<()> {
+        let output = if task.delete_inputs {
+            Vec::new()
+        } else if let Some(output) = self.trivial_move(snapshot, task) {
+            output
+        } else {
+            self.compact(snapshot, task)?
+        };
+        let removed = task.input_sst_ids().collect::<Vec<_>>();
+        let added = output.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+        {
+            let _state_lock = self.state_lock.lock();
+            self.manifest.add_record(ManifestRecord::Compaction {
+                level: task.output_level,
+                removed: removed.clone(),
+                added: added.clone(),
+            })?;
+            let mut guard = self.inner.write();
+            let snapshot = self
+                .compaction_controller
+                .apply_compaction_result(&guard, task, output);
+            *guard = Arc::new(snapshot);
+        }
+        // Readers that still hold the old snapshot keep the files open, so they can read them
+        // until they are done.
+        for id in removed {
+            if !added.contains(&id) {
+                std::fs::remove_file(self.path_of_sst(id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// If `task` has a single sorted run that overlaps with nothing in the output level, the
+    /// output is just the input SSTs, which can be moved to the output level without rewriting
+    /// them. Not done with a compaction filter, which has to see every entry.
+    fn trivial_move(
+        &self,
+        snapshot: &LsmStorageInner,
+        task: &CompactionTask,
+    ) -> Option<Vec<Arc<SsTable>>> {
+        if self.options.compaction_filter.is_some() {
+            return None;
+        }
+        let mut runs = task.inputs.iter().filter(|run| !run.is_empty());
+        let (Some(run), None) = (runs.next(), runs.next()) else {
+            return None;
+        };
+        let ssts = run
+            .iter()
+            .map(|id| snapshot.sst_by_id(*id).cloned())
+            .collect::<Option<Vec<_>>>()?;
+        let output_level = &snapshot.levels[task.output_level - 1];
+        let overlaps = ssts
+            .iter()
+            .any(|sst| !overlapping_ssts(output_level, sst.first_key(), sst.last_key()).is_empty());
+        if overlaps {
+            return None;
+        }
+        Some(ssts)
+    }
+
+    /// Merge the input SSTs of `task` into new SSTs, keeping only the latest version of each key.
+    /// Entries are passed through the compaction filter, if any. Tombstones are dropped as well if
+    /// the output goes to the bottommost level.
+    ///
+    /// The key space is split into up to `max_subcompactions` disjoint ranges, which are merged in
+    /// parallel into separate SSTs.
+    fn compact(
+        &self,
+        snapshot: &LsmStorageInner,
+        task: &CompactionTask,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let ssts = task
+            .input_sst_ids()
+            .map(|id| {
+                snapshot
+                    .sst_by_id(id)
+                    .unwrap_or_else(|| panic!("SST {} not found", id))
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        let bottommost = is_bottommost(snapshot, task);
+        // Output SSTs are preferably split where the SSTs of the next level start, so that each
+        // of them overlaps with fewer SSTs when it is compacted further down.
+        let next_level_keys: Vec<Bytes> = snapshot
+            .levels
+            .get(task.output_level)
+            .map(|level| level.iter().map(|sst| sst.first_key().clone()).collect())
+            .unwrap_or_default();
+        let boundaries = subcompaction_boundaries(&ssts, self.options.max_subcompactions);
+        let ranges = (0..=boundaries.len())
+            .map(|i| {
+                let lower = i.checked_sub(1).map(|i| &boundaries[i][..]);
+                let upper = boundaries.get(i).map(|key| &key[..]);
+                (lower, upper)
+            })
+            .collect::<Vec<_>>();
+
+        let subcompact = |lower, upper| {
+            let mut output = Vec::new();
+            let result = self.subcompact(
+                &ssts,
+                task,
+                lower,
+                upper,
+                bottommost,
+                &next_level_keys,
+                &mut output,
+            );
+            (output, result)
+        };
+        let results = if boundaries.is_empty() {
+            vec![subcompact(None, None)]
+        } else {
+            // At most `max_subcompactions` workers, each taking the next range not started yet.
+            let num_workers = self.options.max_subcompactions.min(ranges.len());
+            let next_range = AtomicUsize::new(0);
+            let mut results = std::thread::scope(|scope| {
+                let handles = (0..num_workers)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            let mut results = Vec::new();
+                            loop {
+                                let idx = next_range.fetch_add(1, Ordering::Relaxed);
+                                let Some(&(lower, upper)) = ranges.get(idx) else {
+                                    return results;
+                                };
+                                results.push((idx, subcompact(lower, upper)));
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().expect("subcompaction panicked"))
+                    .collect::<Vec<_>>()
+            });
+            // Output SSTs must be in key order.
+            results.sort_by_key(|(idx, _)| *idx);
+            results.into_iter().map(|(_, result)| result).collect()
+        };
+        let mut output = Vec::new();
+        let mut error = None;
+        for (ssts, result) in results {
+            output.extend(ssts);
+            if let Err(e) = result {
+                error = error.or(Some(e));
+            }
+        }
+        if let Some(e) = error {
+            // The SSTs written so far are not referenced by the manifest.
+            for sst in output {
+                let _ = std::fs::remove_file(self.path_of_sst(sst.sst_id()));
+            }
+            return Err(e);
+        }
+        Ok(output)
+    }
+
+    /// Merge the keys in `[lower, upper)` of the input SSTs of `task` into `output`. `None` means
+    /// unbounded.
+    ///
+    /// A new SST is started once the current one reaches `target_sst_size`. Past half of the
+    /// target size, it is also started where an SST in the next level starts. It is never
+    /// started earlier, to avoid tiny SSTs.
+    #[allow(clippy::too_many_arguments)]
+    fn subcompact(
+        &self,
+        ssts: &[Arc<SsTable>],
+        task: &CompactionTask,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+        bottommost: bool,
+        next_level_keys: &[Bytes],
+        output: &mut Vec<Arc<SsTable>>,
+    ) -> Result<()> {
+        let mut iters = Vec::new();
+        for sst in ssts {
+            let rate_limiter = self.options.rate_limiter.clone();
+            let iter = match lower {
+                Some(key) => SsTableIterator::create_and_seek_to_key_with_rate_limiter(
+                    sst.clone(),
+                    key,
+                    rate_limiter,
+                )?,
+                None => SsTableIterator::create_and_seek_to_first_with_rate_limiter(
+                    sst.clone(),
+                    rate_limiter,
+                )?,
+            };
+            iters.push(Box::new(iter));
+        }
+        // Newer runs come first, so they win when the same key occurs in multiple runs.
+        let mut iter = MergeIterator::create(iters);
+
+        let target_sst_size = self.options.target_sst_size;
+        let mut builder: Option<SsTableBuilder> = None;
+        // The next-level SSTs starting at or before the last key written.
+        let mut next_level_idx = 0;
+        let mut num_dropped_tombstones = 0;
+        while iter.is_valid() && upper.map_or(true, |upper| iter.key() < upper) {
+            let decision = match &self.options.compaction_filter {
+                Some(filter) if !iter.value().is_empty() => {
+                    filter.filter(task.output_level, iter.key(), iter.value())
+                }
+                _ => CompactionFilterDecision::Keep,
+            };
+            let changed_value;
+            let value = match decision {
+                CompactionFilterDecision::Keep => iter.value(),
+                CompactionFilterDecision::Remove => &[],
+                CompactionFilterDecision::ChangeValue(value) => {
+                    changed_value = value;
+                    &changed_value[..]
+                }
+            };
+            if bottommost && value.is_empty() {
+                if iter.value().is_empty() {
+                    num_dropped_tombstones += 1;
+                }
+            } else {
+                let prev_next_level_idx = next_level_idx;
+                next_level_idx +=
+                    next_level_keys[next_level_idx..].partition_point(|key| key <= iter.key());
+                if let Some(size) = builder.as_ref().map(SsTableBuilder::estimated_size) {
+                    let next_level_boundary = next_level_idx != prev_next_level_idx;
+                    if size >= target_sst_size
+                        || (next_level_boundary && size >= target_sst_size / 2)
+                    {
+                        output.push(self.build_sst(builder.take().unwrap())?);
+                    }
+                }
+                builder
+                    .get_or_insert_with(|| self.new_sst_builder())
+                    .add(iter.key(), value);
+            }
+            iter.next()?;
+        }
+        self.num_dropped_tombstones
+            .fetch_add(num_dropped_tombstones, Ordering::Relaxed);
+        if let Some(builder) = builder {
+            output.push(self.build_sst(builder)?);
+        }
+        Ok(())
+    }
+
+    fn build_sst(&self, builder: SsTableBuilder) -> Result<Arc<SsTable>> {
+        let id = self.allocate_sst_id();
+        let sst = builder.build_with_rate_limiter(
+            id,
+            Some(self.block_cache.clone()),
+            self.path_of_sst(id),
+            self.options.rate_limiter.as_deref(),
+            IoPriority::Low,
+        )?;
+        Ok(Arc::new(sst))
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::{Duration, SystemTime};
+
+use super::{CompactionStrategy, CompactionTask};
+use crate::lsm_storage::LsmStorageInner;
+
+/// Options of FIFO compaction.
+#[derive(Debug, Clone)]
+pub struct FifoCompactionOptions {
+    /// The oldest SSTs are deleted once the total size of all SSTs exceeds this limit, in bytes.
+    pub max_table_files_size: u64,
+    /// SSTs older than this are deleted. `None` keeps SSTs regardless of their age.
+    pub ttl: Option<Duration>,
+}
+
+impl Default for FifoCompactionOptions {
+    fn default() -> Self {
+        Self {
+            max_table_files_size: 1 << 30, // 1GB
+            ttl: None,
+        }
+    }
+}
+
+/// FIFO compaction never merges anything. All SSTs stay in L0, and the oldest ones are deleted
+/// once the data grows too large or too old. Meant for data that is only kept for a while, such
+/// as logs or metrics.
+#[derive(Debug)]
+pub struct FifoCompactionStrategy {
+    options: FifoCompactionOptions,
+}
+
+impl FifoCompactionStrategy {
+    pub fn new(options: FifoCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    fn is_expired(&self, creation_time: SystemTime) -> bool {
+        self.options.ttl.map_or(false, |ttl| {
+            creation_time
+                .elapsed()
+                .map_or(false, |elapsed| elapsed > ttl)
+        })
+    }
+}
+
+impl CompactionStrategy for FifoCompactionStrategy {
+    fn generate_compaction_task(&self, snapshot: &LsmStorageInner) -> Option<CompactionTask> {
+        let mut total_size = snapshot
+            .l0_sstables
+            .iter()
+            .map(|sst| sst.table_size())
+            .sum::<u64>();
+        // L0 is ordered from the oldest SST to the newest.
+        let mut deleted = Vec::new();
+        for sst in &snapshot.l0_sstables {
+            if total_size <= self.options.max_table_files_size
+                && !self.is_expired(sst.creation_time())
+            {
+                break;
+            }
+            total_size -= sst.table_size();
+            deleted.push(vec![sst.sst_id()]);
+        }
+        if deleted.is_empty() {
+            return None;
+        }
+        deleted.reverse();
+        Some(CompactionTask {
+            inputs: deleted,
+            // Nothing is written, any level works.
+            output_level: 1,
+            delete_inputs: true,
+        })
+    }
+
+    /// Deleting SSTs is cheap, so writes are never held back.
+    fn pending_compaction_bytes(&self, _snapshot: &LsmStorageInner) -> u64 {
+        0
+    }
+}
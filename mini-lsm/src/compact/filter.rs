@@ -0,0 +1,20 @@
+use bytes::Bytes;
+
+/// What a [`CompactionFilter`] does with an entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionFilterDecision {
+    /// Write the entry to the output as it is.
+    Keep,
+    /// Remove the entry. Unless the output goes to the bottommost level, a tombstone is written
+    /// instead, so that older versions of the key below the output do not show up again.
+    Remove,
+    /// Replace the value of the entry. An empty value removes the entry, like a delete.
+    ChangeValue(Bytes),
+}
+
+/// A user-supplied callback that sees every entry written by compactions, e.g. to clean up
+/// expired data without issuing deletes. Tombstones are not passed to the filter.
+pub trait CompactionFilter: Send + Sync + std::fmt::Debug {
+    /// Decide what to do with the latest version of `key` while compacting into `level`.
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> CompactionFilterDecision;
+}
@@ -0,0 +1,122 @@
+use super::{overlapping_ssts, CompactionStrategy, CompactionTask};
+use crate::lsm_storage::{LsmStorageInner, NUM_LEVELS};
+
+/// Options of leveled compaction.
+#[derive(Debug, Clone)]
+pub struct LeveledCompactionOptions {
+    /// L0 is compacted into L1 once it has this many SSTs.
+    pub level0_file_num_compaction_trigger: usize,
+    /// Target size of L1, in bytes.
+    pub base_level_size: u64,
+    /// The target size of each level is this many times the target size of the level above.
+    pub level_size_multiplier: u64,
+}
+
+impl Default for LeveledCompactionOptions {
+    fn default() -> Self {
+        Self {
+            level0_file_num_compaction_trigger: 4,
+            base_level_size: 8 << 20, // 8MB
+            level_size_multiplier: 10,
+        }
+    }
+}
+
+/// Leveled compaction keeps each of L1 - L6 a sorted run of non-overlapping SSTs. L0 is merged
+/// into L1 once it has too many SSTs, and data is pushed down from a level to the next one once
+/// the level grows larger than its target size.
+#[derive(Debug)]
+pub struct LeveledCompactionStrategy {
+    options: LeveledCompactionOptions,
+}
+
+impl LeveledCompactionStrategy {
+    pub fn new(options: LeveledCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    fn target_size(&self, level: usize) -> u64 {
+        self.options.base_level_size * self.options.level_size_multiplier.pow(level as u32 - 1)
+    }
+}
+
+impl CompactionStrategy for LeveledCompactionStrategy {
+    fn generate_compaction_task(&self, snapshot: &LsmStorageInner) -> Option<CompactionTask> {
+        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+            // Merge all L0 SSTs and the L1 SSTs overlapping with them into L1.
+            let first_key = snapshot
+                .l0_sstables
+                .iter()
+                .map(|sst| sst.first_key())
+                .min()?;
+            let last_key = snapshot
+                .l0_sstables
+                .iter()
+                .map(|sst| sst.last_key())
+                .max()?;
+            let mut inputs = snapshot
+                .l0_sstables
+                .iter()
+                .rev()
+                .map(|sst| vec![sst.sst_id()])
+                .collect::<Vec<_>>();
+            inputs.push(overlapping_ssts(&snapshot.levels[0], first_key, last_key));
+            inputs.retain(|run| !run.is_empty());
+            return Some(CompactionTask {
+                inputs,
+                output_level: 1,
+                delete_inputs: false,
+            });
+        }
+
+        // Push data down from the level exceeding its target size the most. The last level has no
+        // level to push to.
+        let mut compact_level = None;
+        let mut max_score = 1.0;
+        for level in 1..NUM_LEVELS {
+            let size: u64 = snapshot.levels[level - 1]
+                .iter()
+                .map(|sst| sst.table_size())
+                .sum();
+            let score = size as f64 / self.target_size(level) as f64;
+            if score > max_score {
+                compact_level = Some(level);
+                max_score = score;
+            }
+        }
+        let level = compact_level?;
+        // Compact the oldest SST of the level with the overlapping SSTs of the next level.
+        let upper = snapshot.levels[level - 1]
+            .iter()
+            .min_by_key(|sst| sst.sst_id())?;
+        let lower = overlapping_ssts(&snapshot.levels[level], upper.first_key(), upper.last_key());
+        let mut inputs = vec![vec![upper.sst_id()], lower];
+        inputs.retain(|run| !run.is_empty());
+        Some(CompactionTask {
+            inputs,
+            output_level: level + 1,
+            delete_inputs: false,
+        })
+    }
+
+    /// The L0 SSTs once L0 reaches the compaction trigger, and the bytes by which each level
+    /// exceeds its target size.
+    fn pending_compaction_bytes(&self, snapshot: &LsmStorageInner) -> u64 {
+        let mut pending_bytes = 0;
+        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+            pending_bytes += snapshot
+                .l0_sstables
+                .iter()
+                .map(|sst| sst.table_size())
+                .sum::<u64>();
+        }
+        for level in 1..NUM_LEVELS {
+            let size: u64 = snapshot.levels[level - 1]
+                .iter()
+                .map(|sst| sst.table_size())
+                .sum();
+            pending_bytes += size.saturating_sub(self.target_size(level));
+        }
+        pending_bytes
+    }
+}
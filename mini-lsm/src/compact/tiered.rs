@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use super::{CompactionStrategy, CompactionTask};
+use crate::lsm_storage::{LsmStorageInner, NUM_LEVELS};
+use crate::table::SsTable;
+
+/// Options of tiered compaction.
+#[derive(Debug, Clone)]
+pub struct TieredCompactionOptions {
+    /// Compaction is triggered once there are at least this many sorted runs.
+    pub num_runs_trigger: usize,
+    /// All sorted runs are merged once the total size of all runs but the oldest one exceeds
+    /// this percentage of the size of the oldest run.
+    pub max_size_amplification_percent: u64,
+    /// A sorted run is merged together with the newer runs before it if its size is at most
+    /// `100 + size_ratio_percent` percent of their total size.
+    pub size_ratio_percent: u64,
+    /// The minimum number of sorted runs merged because of the size ratio.
+    pub min_merge_width: usize,
+}
+
+impl Default for TieredCompactionOptions {
+    fn default() -> Self {
+        Self {
+            num_runs_trigger: 4,
+            max_size_amplification_percent: 200,
+            size_ratio_percent: 1,
+            min_merge_width: 2,
+        }
+    }
+}
+
+/// A sorted run: an L0 SST, or all SSTs of a level.
+struct SortedRun<'a> {
+    /// `0` for an L0 SST.
+    level: usize,
+    ssts: &'a [Arc<SsTable>],
+}
+
+impl SortedRun<'_> {
+    fn size(&self) -> u64 {
+        self.ssts.iter().map(|sst| sst.table_size()).sum()
+    }
+}
+
+/// Tiered (universal) compaction treats each L0 SST and each non-empty level as a sorted run, and
+/// merges runs of similar sizes, trading read amplification for lower write amplification.
+///
+/// Runs are kept in order of age: L0 SSTs are the newest, followed by L1, L2, etc. A compaction
+/// always merges a number of the newest runs, and its output replaces them in the level of the
+/// oldest one, so that the order is preserved.
+#[derive(Debug)]
+pub struct TieredCompactionStrategy {
+    options: TieredCompactionOptions,
+}
+
+impl TieredCompactionStrategy {
+    pub fn new(options: TieredCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    /// Build the task merging the newest `num_runs` runs, adding runs as needed so that the output
+    /// can be placed in a level without breaking the order of runs.
+    fn merge_newest_runs(runs: &[SortedRun], mut num_runs: usize) -> CompactionTask {
+        // The output is older than any L0 SST left out, so all of L0 has to be merged.
+        while num_runs < runs.len() && runs[num_runs].level == 0 {
+            num_runs += 1;
+        }
+        let output_level = match runs[num_runs - 1].level {
+            // Only L0 SSTs are merged: put the output right above the newest level, merging that
+            // level as well if it is L1.
+            0 => match runs.get(num_runs) {
+                Some(run) if run.level == 1 => {
+                    num_runs += 1;
+                    1
+                }
+                Some(run) => run.level - 1,
+                None => NUM_LEVELS,
+            },
+            level => level,
+        };
+        CompactionTask {
+            inputs: runs[..num_runs]
+                .iter()
+                .map(|run| run.ssts.iter().map(|sst| sst.sst_id()).collect())
+                .collect(),
+            output_level,
+            delete_inputs: false,
+        }
+    }
+}
+
+impl CompactionStrategy for TieredCompactionStrategy {
+    fn generate_compaction_task(&self, snapshot: &LsmStorageInner) -> Option<CompactionTask> {
+        // Sorted runs, from the newest to the oldest.
+        let runs = snapshot
+            .l0_sstables
+            .iter()
+            .rev()
+            .map(|sst| SortedRun {
+                level: 0,
+                ssts: std::slice::from_ref(sst),
+            })
+            .chain(
+                snapshot
+                    .levels
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, ssts)| !ssts.is_empty())
+                    .map(|(idx, ssts)| SortedRun {
+                        level: idx + 1,
+                        ssts,
+                    }),
+            )
+            .collect::<Vec<_>>();
+        if runs.len() < self.options.num_runs_trigger.max(2) {
+            return None;
+        }
+
+        // Space amplification: merge all runs if the newer runs take too much space compared to
+        // the oldest one, which holds most of the data after a full compaction.
+        let oldest_size = runs.last().unwrap().size();
+        let newer_size: u64 = runs[..runs.len() - 1].iter().map(SortedRun::size).sum();
+        if newer_size * 100 >= self.options.max_size_amplification_percent * oldest_size {
+            return Some(Self::merge_newest_runs(&runs, runs.len()));
+        }
+
+        // Size ratio: merge the newest runs as long as the next run is not much larger than all
+        // runs before it.
+        let mut total_size = runs[0].size();
+        let mut num_runs = 1;
+        while num_runs < runs.len()
+            && runs[num_runs].size() * 100 <= total_size * (100 + self.options.size_ratio_percent)
+        {
+            total_size += runs[num_runs].size();
+            num_runs += 1;
+        }
+        if num_runs >= self.options.min_merge_width.max(2) {
+            return Some(Self::merge_newest_runs(&runs, num_runs));
+        }
+
+        // Otherwise, merge just enough of the newest runs to bring the number of runs below the
+        // trigger.
+        let num_runs = runs.len() + 2 - self.options.num_runs_trigger.max(2);
+        Some(Self::merge_newest_runs(&runs, num_runs))
+    }
+}
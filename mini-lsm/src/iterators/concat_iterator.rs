@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::StorageIterator;
+use crate::table::{SsTable, SsTableIterator};
+
+/// Iterates over a sorted run of SSTs with non-overlapping key ranges, such as a level, one SST
+/// after another. Each SST is only opened when the iterator reaches it.
+pub struct SstConcatIterator {
+    current: Option<SsTableIterator>,
+    /// The SST to open once `current` is exhausted.
+    next_sst_idx: usize,
+    sstables: Vec<Arc<SsTable>>,
+}
+
+impl SstConcatIterator {
+    /// Create an iterator over `sstables`, which must be sorted by key and must not overlap.
+    pub fn create_and_seek_to_first(sstables: Vec<Arc<SsTable>>) -> Result<Self> {
+        let mut iter = Self {
+            current: None,
+            next_sst_idx: 0,
+            sstables,
+        };
+        iter.move_until_valid()?;
+        Ok(iter)
+    }
+
+    /// Create an iterator over `sstables` and seek to the first key-value pair which >= `key`.
+    /// Only the SST that may contain `key` is opened.
+    pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: &[u8]) -> Result<Self> {
+        let idx = sstables.partition_point(|sst| &sst.last_key()[..] < key);
+        let current = match sstables.get(idx) {
+            Some(sst) => Some(SsTableIterator::create_and_seek_to_key(sst.clone(), key)?),
+            None => None,
+        };
+        let mut iter = Self {
+            current,
+            next_sst_idx: idx + 1,
+            sstables,
+        };
+        iter.move_until_valid()?;
+        Ok(iter)
+    }
+
+    /// Open the following SSTs until `current` points to a key, or there are no more SSTs.
+    fn move_until_valid(&mut self) -> Result<()> {
+        while !self.current.as_ref().map_or(false, |iter| iter.is_valid()) {
+            let Some(sst) = self.sstables.get(self.next_sst_idx) else {
+                self.current = None;
+                return Ok(());
+            };
+            self.current = Some(SsTableIterator::create_and_seek_to_first(sst.clone())?);
+            self.next_sst_idx += 1;
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for SstConcatIterator {
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().value()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.current.as_ref().unwrap().key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if let Some(iter) = self.current.as_mut() {
+            iter.next()?;
+            self.move_until_valid()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use tempfile::{tempdir, TempDir};
+
+use super::*;
+use crate::iterators::concat_iterator::SstConcatIterator;
+use crate::table::{SsTable, SsTableBuilder};
+
+fn key_of(idx: usize) -> Bytes {
+    Bytes::from(format!("key_{:03}", idx))
+}
+
+fn value_of(idx: usize) -> Bytes {
+    Bytes::from(format!("value_{:03}", idx))
+}
+
+/// Three SSTs holding the keys 0 - 9, 20 - 29 and 40 - 49.
+fn generate_ssts() -> (TempDir, Vec<Arc<SsTable>>) {
+    let dir = tempdir().unwrap();
+    let ssts = (0..3)
+        .map(|sst| {
+            let mut builder = SsTableBuilder::new(64);
+            for idx in sst * 20..sst * 20 + 10 {
+                builder.add(&key_of(idx), &value_of(idx));
+            }
+            let path = dir.path().join(format!("{}.sst", sst));
+            Arc::new(builder.build_for_test(path).unwrap())
+        })
+        .collect();
+    (dir, ssts)
+}
+
+fn check_iter_result(iter: impl StorageIterator, expected: impl Iterator<Item = usize>) {
+    let mut iter = iter;
+    for idx in expected {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+fn all_keys() -> impl Iterator<Item = usize> {
+    (0..10).chain(20..30).chain(40..50)
+}
+
+#[test]
+fn test_concat_iterator_seek_to_first() {
+    let (_dir, ssts) = generate_ssts();
+    let iter = SstConcatIterator::create_and_seek_to_first(ssts).unwrap();
+    check_iter_result(iter, all_keys());
+}
+
+#[test]
+fn test_concat_iterator_seek_to_key() {
+    let (_dir, ssts) = generate_ssts();
+    let seek = |idx: usize| SstConcatIterator::create_and_seek_to_key(ssts.clone(), &key_of(idx));
+    check_iter_result(seek(0).unwrap(), all_keys());
+    check_iter_result(seek(25).unwrap(), all_keys().filter(|idx| *idx >= 25));
+    // Between two SSTs.
+    check_iter_result(seek(15).unwrap(), all_keys().filter(|idx| *idx >= 20));
+    check_iter_result(seek(49).unwrap(), 49..50);
+    check_iter_result(seek(50).unwrap(), 0..0);
+}
+
+#[test]
+fn test_concat_iterator_empty() {
+    let iter = SstConcatIterator::create_and_seek_to_first(Vec::new()).unwrap();
+    assert!(!iter.is_valid());
+    let iter = SstConcatIterator::create_and_seek_to_key(Vec::new(), b"key").unwrap();
+    assert!(!iter.is_valid());
+}
@@ -1,9 +1,14 @@
 pub mod block;
+pub mod compact;
 pub mod iterators;
 pub mod lsm_iterator;
 pub mod lsm_storage;
+pub mod manifest;
 pub mod mem_table;
+pub mod rate_limiter;
 pub mod table;
+pub mod wal;
+pub mod write_batch;
 
 #[cfg(test)]
 mod tests;
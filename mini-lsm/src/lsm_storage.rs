@@ -1,67 +1,533 @@
+use std::collections::HashMap;
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
-use parking_lot::{Mutex, RwLock};
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 
 use crate::block::Block;
+use crate::compact::{CompactionController, CompactionFilter, CompactionOptions};
+use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::manifest::{Manifest, ManifestRecord};
 use crate::mem_table::{map_bound, MemTable};
-use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::rate_limiter::{IoPriority, RateLimiter};
+use crate::table::{
+    FileObject, PrefixExtractor, SsTable, SsTableBuilder, SsTableIterator, TEMP_FILE_EXTENSION,
+};
+use crate::wal::MAX_KEY_VALUE_LEN;
+use crate::write_batch::WriteBatch;
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
+/// Number of levels below L0.
+pub(crate) const NUM_LEVELS: usize = 6;
+
 #[derive(Clone)]
 pub struct LsmStorageInner {
     /// The current memtable.
-    memtable: Arc<MemTable>,
+    pub(crate) memtable: Arc<MemTable>,
     /// Immutable memTables, from earliest to latest.
-    imm_memtables: Vec<Arc<MemTable>>,
+    pub(crate) imm_memtables: Vec<Arc<MemTable>>,
     /// L0 SsTables, from earliest to latest.
-    l0_sstables: Vec<Arc<SsTable>>,
+    pub(crate) l0_sstables: Vec<Arc<SsTable>>,
     /// L1 - L6 SsTables, sorted by key range.
-    #[allow(dead_code)]
-    levels: Vec<Vec<Arc<SsTable>>>,
-    /// The next SSTable ID.
-    next_sst_id: usize,
+    pub(crate) levels: Vec<Vec<Arc<SsTable>>>,
+    /// The next SSTable ID. Memtables share the same ID space, as each memtable is flushed into
+    /// the SST with its ID.
+    pub(crate) next_sst_id: usize,
 }
 
 impl LsmStorageInner {
-    fn create() -> Self {
+    /// L0 SSTs, from earliest to latest.
+    pub fn l0_sstables(&self) -> &[Arc<SsTable>] {
+        &self.l0_sstables
+    }
+
+    /// SSTs of L1 - L6, each level sorted by key range.
+    pub fn levels(&self) -> &[Vec<Arc<SsTable>>] {
+        &self.levels
+    }
+}
+
+/// Options of the storage engine.
+#[derive(Debug, Clone)]
+pub struct LsmStorageOptions {
+    /// Target size of data blocks in SSTs, in bytes.
+    pub block_size: usize,
+    /// SSTs have a bloom filter over their keys using about this many bits per key, so that
+    /// lookups skip SSTs without the key. `0` means no filter.
+    pub bloom_bits_per_key: usize,
+    /// SSTs also have a bloom filter over the prefixes of their keys extracted by this, so that
+    /// scans within a prefix skip SSTs without the prefix. It uses `bloom_bits_per_key` bits per
+    /// prefix, so it requires `bloom_bits_per_key` to be non-zero.
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// The current memtable is frozen into an immutable memtable once its approximate size
+    /// reaches this limit, in bytes.
+    pub memtable_size_limit: usize,
+    pub compaction_options: CompactionOptions,
+    /// Called on every entry written by compactions.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Compactions split their output into SSTs of about this size, in bytes.
+    pub target_sst_size: usize,
+    /// Limits the I/O of flushes and compactions. Flushes go first.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// A compaction is split into up to this many key ranges, which are compacted in parallel.
+    pub max_subcompactions: usize,
+    /// Writes are stopped once there are this many immutable memtables, until flushes catch up.
+    pub max_imm_memtables: usize,
+    /// Writes are slowed down once L0 has this many SSTs, until compaction catches up.
+    pub level0_slowdown_writes_trigger: usize,
+    /// Writes are stopped once L0 has this many SSTs, until compaction catches up.
+    pub level0_stop_writes_trigger: usize,
+    /// Writes are slowed down once the estimated bytes waiting for compaction reach this limit.
+    pub soft_pending_compaction_bytes_limit: u64,
+    /// Writes are stopped once the estimated bytes waiting for compaction reach this limit.
+    pub hard_pending_compaction_bytes_limit: u64,
+}
+
+impl Default for LsmStorageOptions {
+    fn default() -> Self {
         Self {
-            memtable: Arc::new(MemTable::create()),
-            imm_memtables: vec![],
-            l0_sstables: vec![],
-            levels: vec![],
-            next_sst_id: 1,
+            block_size: 4096,
+            bloom_bits_per_key: 10,
+            prefix_extractor: None,
+            memtable_size_limit: 2 << 20, // 2MB
+            compaction_options: CompactionOptions::default(),
+            compaction_filter: None,
+            target_sst_size: 2 << 20, // 2MB
+            rate_limiter: None,
+            max_subcompactions: 1,
+            max_imm_memtables: 4,
+            level0_slowdown_writes_trigger: 20,
+            level0_stop_writes_trigger: 36,
+            soft_pending_compaction_bytes_limit: 64 << 30, // 64GB
+            hard_pending_compaction_bytes_limit: 256 << 30, // 256GB
         }
     }
 }
 
+/// Options of a single write.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Return only after the write is persisted to disk with `fsync`. Otherwise, the write
+    /// survives a process crash, but may be lost if the machine crashes.
+    pub sync: bool,
+}
+
 /// The storage interface of the LSM tree.
 pub struct LsmStorage {
-    inner: Arc<RwLock<Arc<LsmStorageInner>>>,
-    flush_lock: Mutex<()>,
-    path: PathBuf,
-    block_cache: Arc<BlockCache>,
+    core: Arc<LsmStorageCore>,
+    /// Stops the background threads when dropped.
+    threads_stop: Mutex<Option<Sender<()>>>,
+    flush_thread: Mutex<Option<JoinHandle<()>>>,
+    compaction_thread: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl LsmStorage {
+    /// Open the storage at `path` with the default options.
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, LsmStorageOptions::default())
+    }
+
+    /// Open the storage at `path`. The structure of the LSM tree is rebuilt from the manifest,
+    /// and the memtables are replayed from their WALs.
+    pub fn open_with_options(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        if options.prefix_extractor.is_some() && options.bloom_bits_per_key == 0 {
+            bail!("prefix_extractor requires bloom_bits_per_key to be non-zero");
+        }
+        let (flush_notifier, flush_rx) = crossbeam_channel::bounded(1);
+        let (compaction_notifier, compaction_rx) = crossbeam_channel::bounded(1);
+        let (threads_stop, stop_rx) = crossbeam_channel::bounded(1);
+        let core = Arc::new(LsmStorageCore::open(
+            path,
+            options,
+            flush_notifier,
+            compaction_notifier,
+        )?);
+        core.update_write_stall();
+        let flush_thread = core.clone().spawn_flush_thread(flush_rx, stop_rx.clone())?;
+        let compaction_thread = core
+            .clone()
+            .spawn_compaction_thread(compaction_rx, stop_rx)?;
         Ok(Self {
-            inner: Arc::new(RwLock::new(Arc::new(LsmStorageInner::create()))),
+            core,
+            threads_stop: Mutex::new(Some(threads_stop)),
+            flush_thread: Mutex::new(Some(flush_thread)),
+            compaction_thread: Mutex::new(Some(compaction_thread)),
+        })
+    }
+
+    /// Get a key from the storage.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.core.get(key)
+    }
+
+    /// Put a key-value pair into the storage.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.core.put(key, value, &WriteOptions::default())
+    }
+
+    /// Put a key-value pair into the storage with the given write options.
+    pub fn put_with_options(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<()> {
+        self.core.put(key, value, options)
+    }
+
+    /// Remove a key from the storage.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.core.delete(key, &WriteOptions::default())
+    }
+
+    /// Remove a key from the storage with the given write options.
+    pub fn delete_with_options(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        self.core.delete(key, options)
+    }
+
+    /// Apply all writes in `batch` atomically.
+    pub fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.core.write(batch, &WriteOptions::default())
+    }
+
+    /// Apply all writes in `batch` atomically with the given write options.
+    pub fn write_with_options(&self, batch: &WriteBatch, options: &WriteOptions) -> Result<()> {
+        self.core.write(batch, options)
+    }
+
+    /// Create an iterator over a range of keys.
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan(lower, upper)
+    }
+
+    /// Persist all writes to disk by calling `fsync` on the WALs.
+    pub fn sync(&self) -> Result<()> {
+        self.core.sync()
+    }
+
+    /// Compact all SSTs overlapping with a range of keys into the bottom level, flushing the
+    /// memtables first if they have keys in the range. Blocks until the compaction is done.
+    /// Not supported with FIFO compaction.
+    pub fn compact_range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<()> {
+        self.core.compact_range(lower, upper)
+    }
+
+    /// Number of tombstones dropped by compactions since the storage was opened. Tombstones are
+    /// only dropped when no older version of the key can exist below the compaction's output.
+    pub fn num_dropped_tombstones(&self) -> u64 {
+        self.core.num_dropped_tombstones.load(Ordering::Relaxed)
+    }
+
+    /// Freeze the current memtable and flush all memtables to L0 SSTs on the caller's thread.
+    pub fn force_flush(&self) -> Result<()> {
+        self.core.force_flush()
+    }
+
+    /// Stop the background threads, flush all immutable memtables, and persist the current
+    /// memtable with its WAL. Writes and flushes fail after closing. Fails if a background flush
+    /// or compaction has failed.
+    pub fn close(&self) -> Result<()> {
+        self.core.close_writes();
+        self.stop_background_threads();
+        self.core.flush_imm_memtables()?;
+        self.core.sync()?;
+        self.core.check_background_error()
+    }
+
+    /// Ids of the SSTs in L0, from earliest to latest, and in each of L1 - L6.
+    #[cfg(test)]
+    pub(crate) fn sst_ids(&self) -> (Vec<usize>, Vec<Vec<usize>>) {
+        let snapshot = self.core.inner.read();
+        let ids = |tables: &[Arc<SsTable>]| tables.iter().map(|table| table.sst_id()).collect();
+        (
+            ids(&snapshot.l0_sstables),
+            snapshot.levels.iter().map(|level| ids(level)).collect(),
+        )
+    }
+
+    /// Number of writers waiting for writes to be resumed.
+    #[cfg(test)]
+    pub(crate) fn num_stopped_writers(&self) -> usize {
+        self.core.num_stopped_writers.load(Ordering::SeqCst)
+    }
+
+    /// Number of times the WALs of the memtables in memory have been flushed to disk with `fsync`.
+    #[cfg(test)]
+    pub(crate) fn num_wal_syncs(&self) -> usize {
+        let snapshot = self.core.inner.read();
+        snapshot
+            .imm_memtables
+            .iter()
+            .chain(std::iter::once(&snapshot.memtable))
+            .map(|memtable| memtable.num_wal_syncs())
+            .sum()
+    }
+
+    /// Commit batches with their `sync` options as a single group, as a group commit leader does.
+    #[cfg(test)]
+    pub(crate) fn commit_group(&self, group: Vec<(WriteBatch, bool)>) -> Result<()> {
+        let group = group
+            .into_iter()
+            .enumerate()
+            .map(|(ticket, (batch, sync))| (ticket as u64, batch, sync))
+            .collect::<Vec<_>>();
+        self.core.commit_group(&group)?;
+        Ok(())
+    }
+
+    /// Run compactions on the caller's thread until there is nothing to compact.
+    #[cfg(test)]
+    pub(crate) fn force_compact(&self) -> Result<()> {
+        self.core.trigger_compaction()
+    }
+
+    fn stop_background_threads(&self) {
+        // Dropping the sender also wakes up the threads.
+        self.threads_stop.lock().take();
+        if let Some(flush_thread) = self.flush_thread.lock().take() {
+            flush_thread.join().expect("flush thread panicked");
+        }
+        if let Some(compaction_thread) = self.compaction_thread.lock().take() {
+            compaction_thread
+                .join()
+                .expect("compaction thread panicked");
+        }
+    }
+}
+
+impl Drop for LsmStorage {
+    fn drop(&mut self) {
+        // Writes not flushed yet are still in the WALs, and will be recovered on the next open.
+        self.stop_background_threads();
+    }
+}
+
+/// The state shared between the storage interface and the background threads.
+pub(crate) struct LsmStorageCore {
+    pub(crate) inner: Arc<RwLock<Arc<LsmStorageInner>>>,
+    flush_lock: Mutex<()>,
+    /// Serializes operations that replace the snapshot, such as freezing a memtable.
+    pub(crate) state_lock: Mutex<()>,
+    /// Serializes compactions.
+    pub(crate) compaction_lock: Mutex<()>,
+    path: PathBuf,
+    pub(crate) block_cache: Arc<BlockCache>,
+    pub(crate) manifest: Manifest,
+    pub(crate) options: LsmStorageOptions,
+    pub(crate) compaction_controller: CompactionController,
+    /// Number of tombstones dropped by compactions into the bottommost level.
+    pub(crate) num_dropped_tombstones: AtomicU64,
+    /// Wakes up the flush thread when a memtable is frozen.
+    flush_notifier: Sender<()>,
+    /// Wakes up the compaction thread when a memtable is flushed.
+    compaction_notifier: Sender<()>,
+    /// Whether writes are slowed down or stopped to let compaction catch up.
+    write_stall: Mutex<WriteStall>,
+    /// Notified when writes are no longer stopped.
+    write_resumed: Condvar,
+    commit_queue: Mutex<CommitQueue>,
+    /// Notified when a group commit finishes.
+    commit_done: Condvar,
+    /// The first error of a background flush or compaction. Once set, all writes fail.
+    background_error: Mutex<Option<String>>,
+    /// Number of writers waiting for writes to be resumed.
+    #[cfg(test)]
+    num_stopped_writers: std::sync::atomic::AtomicUsize,
+}
+
+/// How writes are held back while compaction falls behind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WriteStall {
+    Normal,
+    Slowdown,
+    Stop,
+}
+
+/// How long each write is delayed while writes are slowed down.
+const WRITE_SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// Writes waiting for a group commit.
+#[derive(Default)]
+struct CommitQueue {
+    /// Batches not taken by a leader yet, with their tickets and whether they need `fsync`.
+    pending: Vec<(u64, WriteBatch, bool)>,
+    next_ticket: u64,
+    /// Whether a leader is committing a group.
+    leader_active: bool,
+    /// Results of batches committed by a leader on behalf of other writers, by ticket. Errors
+    /// are kept as messages, as they are shared by the whole group.
+    committed: HashMap<u64, std::result::Result<(), String>>,
+    /// Set when the storage is closed. No batches are queued after it.
+    closed: bool,
+}
+
+impl LsmStorageCore {
+    fn open(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        flush_notifier: Sender<()>,
+        compaction_notifier: Sender<()>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        // Temporary files are left behind by a crash in the middle of writing an SST.
+        for entry in std::fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            if entry_path
+                .extension()
+                .map_or(false, |ext| ext == TEMP_FILE_EXTENSION)
+            {
+                std::fs::remove_file(&entry_path)?;
+            }
+        }
+        let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache
+
+        let manifest_path = path.join("MANIFEST");
+        let (manifest, records) = if manifest_path.exists() {
+            Manifest::recover(&manifest_path)?
+        } else {
+            (Manifest::create(&manifest_path)?, Vec::new())
+        };
+
+        // Replay the manifest to find out which memtables and SSTs are alive.
+        let mut memtable_ids = Vec::new();
+        let mut l0_sst_ids = Vec::new();
+        let mut level_sst_ids = vec![Vec::new(); NUM_LEVELS];
+        let mut next_sst_id = 1;
+        for record in records {
+            match record {
+                ManifestRecord::NewMemtable(id) => {
+                    memtable_ids.push(id);
+                    next_sst_id = next_sst_id.max(id + 1);
+                }
+                ManifestRecord::Flush(id) => {
+                    memtable_ids.retain(|x| *x != id);
+                    l0_sst_ids.push(id);
+                }
+                ManifestRecord::Compaction {
+                    level,
+                    removed,
+                    added,
+                } => {
+                    l0_sst_ids.retain(|x| !removed.contains(x));
+                    for ids in &mut level_sst_ids {
+                        ids.retain(|x| !removed.contains(x));
+                    }
+                    next_sst_id = added.iter().fold(next_sst_id, |acc, id| acc.max(id + 1));
+                    level_sst_ids[level - 1].extend(added);
+                }
+            }
+        }
+
+        // Remove SSTs and WALs the manifest does not reference, e.g. the output of an unfinished
+        // flush or compaction, or the WAL of a flushed memtable, and never reuse their ids.
+        for entry in std::fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            let (Some(stem), Some(ext)) = (entry_path.file_stem(), entry_path.extension()) else {
+                continue;
+            };
+            let Some(id) = stem.to_str().and_then(|stem| stem.parse::<usize>().ok()) else {
+                continue;
+            };
+            let referenced = if ext == "sst" {
+                l0_sst_ids.contains(&id) || level_sst_ids.iter().any(|ids| ids.contains(&id))
+            } else if ext == "wal" {
+                memtable_ids.contains(&id)
+            } else {
+                continue;
+            };
+            next_sst_id = next_sst_id.max(id + 1);
+            if !referenced {
+                std::fs::remove_file(&entry_path)?;
+            }
+        }
+
+        let open_sst = |id| -> Result<Arc<SsTable>> {
+            let file = FileObject::open(&Self::path_of_sst_static(path, id))?;
+            Ok(Arc::new(SsTable::open(
+                id,
+                Some(block_cache.clone()),
+                file,
+            )?))
+        };
+        let l0_sstables = l0_sst_ids
+            .into_iter()
+            .map(open_sst)
+            .collect::<Result<Vec<_>>>()?;
+        let mut levels = level_sst_ids
+            .into_iter()
+            .map(|ids| ids.into_iter().map(open_sst).collect::<Result<Vec<_>>>())
+            .collect::<Result<Vec<_>>>()?;
+        for level in &mut levels {
+            level.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        }
+
+        let mut imm_memtables = Vec::new();
+        for id in memtable_ids {
+            let wal_path = Self::path_of_wal_static(path, id);
+            // The WAL of an empty memtable is removed without flushing.
+            if wal_path.exists() {
+                imm_memtables.push(Arc::new(MemTable::recover_from_wal(id, wal_path)?));
+            }
+        }
+
+        // Keep writing to the latest memtable, or create a new one.
+        let memtable = match imm_memtables.pop() {
+            Some(memtable) => memtable,
+            None => {
+                let id = next_sst_id;
+                next_sst_id += 1;
+                // Record the memtable first, so that its id is never reused even if creating the
+                // WAL fails.
+                manifest.add_record(ManifestRecord::NewMemtable(id))?;
+                Arc::new(MemTable::create_with_wal(
+                    id,
+                    Self::path_of_wal_static(path, id),
+                )?)
+            }
+        };
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Arc::new(LsmStorageInner {
+                memtable,
+                imm_memtables,
+                l0_sstables,
+                levels,
+                next_sst_id,
+            }))),
             flush_lock: Mutex::new(()),
-            path: path.as_ref().to_path_buf(),
-            block_cache: Arc::new(BlockCache::new(1 << 20)), // 4GB block cache
+            state_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
+            path: path.to_path_buf(),
+            block_cache,
+            manifest,
+            compaction_controller: CompactionController::new(&options.compaction_options),
+            options,
+            num_dropped_tombstones: AtomicU64::new(0),
+            flush_notifier,
+            compaction_notifier,
+            write_stall: Mutex::new(WriteStall::Normal),
+            write_resumed: Condvar::new(),
+            commit_queue: Mutex::new(CommitQueue::default()),
+            commit_done: Condvar::new(),
+            background_error: Mutex::new(None),
+            #[cfg(test)]
+            num_stopped_writers: Default::default(),
         })
     }
 
-    /// Get a key from the storage. In day 7, this can be further optimized by using a bloom filter.
+    /// Get a key from the storage. SSTs whose bloom filter rules out the key are skipped.
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
         let snapshot = {
             let guard = self.inner.read();
@@ -89,93 +555,460 @@ impl LsmStorage {
         let mut iters = Vec::new();
         iters.reserve(snapshot.l0_sstables.len());
         for table in snapshot.l0_sstables.iter().rev() {
+            if key < &table.first_key()[..]
+                || key > &table.last_key()[..]
+                || !table.may_contain_key(key)
+            {
+                continue;
+            }
             iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
                 table.clone(),
                 key,
             )?));
         }
+        // SSTs in a level don't overlap, at most one of them may contain the key.
+        for level in &snapshot.levels {
+            let idx = level.partition_point(|table| &table.first_key()[..] <= key);
+            if let Some(table) = idx.checked_sub(1).map(|idx| &level[idx]) {
+                if key <= &table.last_key()[..] && table.may_contain_key(key) {
+                    iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
+                        table.clone(),
+                        key,
+                    )?));
+                }
+            }
+        }
         let iter = MergeIterator::create(iters);
-        if iter.is_valid() {
+        if iter.is_valid() && iter.key() == key {
+            if iter.value().is_empty() {
+                // found tomestone, return key not exists
+                return Ok(None);
+            }
             return Ok(Some(Bytes::copy_from_slice(iter.value())));
         }
         Ok(None)
     }
 
     /// Put a key-value pair into the storage by writing into the current memtable.
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        assert!(!value.is_empty(), "value cannot be empty");
-        assert!(!key.is_empty(), "key cannot be empty");
+    pub fn put(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(&batch, options)
+    }
 
-        let guard = self.inner.read();
-        guard.memtable.put(key, value);
+    /// Remove a key from the storage by writing an empty value.
+    pub fn delete(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(&batch, options)
+    }
 
+    /// Apply all writes in `batch` atomically. The whole batch goes to the current memtable, so
+    /// that readers see either all of it or none of it. Fails without writing anything if a key
+    /// or a value is longer than [`MAX_KEY_VALUE_LEN`] bytes.
+    ///
+    /// Concurrent writes are committed in groups. A writer queues its batch, and if no group
+    /// commit is in progress, it becomes the leader: it takes every queued batch, logs them to the
+    /// WAL in one write with at most one `fsync`, applies them to the memtable, and wakes up the
+    /// other writers of the group.
+    pub fn write(&self, batch: &WriteBatch, options: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // Check before queueing, so that an oversized write does not fail the whole group.
+        for (key, value) in batch.entries() {
+            if key.len() > MAX_KEY_VALUE_LEN {
+                bail!(
+                    "key of {} bytes exceeds the maximum of {} bytes",
+                    key.len(),
+                    MAX_KEY_VALUE_LEN
+                );
+            }
+            if value.len() > MAX_KEY_VALUE_LEN {
+                bail!(
+                    "value of {} bytes exceeds the maximum of {} bytes",
+                    value.len(),
+                    MAX_KEY_VALUE_LEN
+                );
+            }
+        }
+        self.wait_for_write_stall()?;
+
+        let mut queue = self.commit_queue.lock();
+        if queue.closed {
+            bail!("storage is closed");
+        }
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, batch.clone(), options.sync));
+        while queue.leader_active {
+            self.commit_done.wait(&mut queue);
+            if let Some(result) = queue.committed.remove(&ticket) {
+                // Committed by another leader.
+                return result.map_err(|e| anyhow!("group commit failed: {}", e));
+            }
+        }
+        queue.leader_active = true;
+        let group = std::mem::take(&mut queue.pending);
+        drop(queue);
+
+        let result = self.commit_group(&group);
+
+        let mut queue = self.commit_queue.lock();
+        queue.leader_active = false;
+        for (other_ticket, _, _) in &group {
+            if *other_ticket != ticket {
+                let other_result = match result {
+                    Ok(_) => Ok(()),
+                    Err(ref e) => Err(format!("{:#}", e)),
+                };
+                queue.committed.insert(*other_ticket, other_result);
+            }
+        }
+        drop(queue);
+        self.commit_done.notify_all();
+
+        let estimated_size = result?;
+        // The batch is committed at this point, so the write succeeds even if freezing fails.
+        self.try_freeze(estimated_size);
         Ok(())
     }
 
-    /// Remove a key from the storage by writing an empty value.
-    pub fn delete(&self, key: &[u8]) -> Result<()> {
-        assert!(!key.is_empty(), "key cannot be empty");
+    /// Refuse all further writes, and wait for the writes already queued to be committed.
+    fn close_writes(&self) {
+        let mut queue = self.commit_queue.lock();
+        queue.closed = true;
+        while queue.leader_active || !queue.pending.is_empty() {
+            self.commit_done.wait(&mut queue);
+        }
+    }
+
+    pub(crate) fn check_not_closed(&self) -> Result<()> {
+        if self.commit_queue.lock().closed {
+            bail!("storage is closed");
+        }
+        Ok(())
+    }
+
+    /// Hold back the caller while writes are slowed down or stopped. Fails if a background flush
+    /// or compaction has failed, as it may never catch up.
+    fn wait_for_write_stall(&self) -> Result<()> {
+        let mut write_stall = self.write_stall.lock();
+        loop {
+            self.check_background_error()?;
+            match *write_stall {
+                WriteStall::Normal => return Ok(()),
+                WriteStall::Slowdown => {
+                    drop(write_stall);
+                    std::thread::sleep(WRITE_SLOWDOWN_DELAY);
+                    return Ok(());
+                }
+                WriteStall::Stop => {
+                    #[cfg(test)]
+                    self.num_stopped_writers.fetch_add(1, Ordering::SeqCst);
+                    self.write_resumed.wait(&mut write_stall);
+                    #[cfg(test)]
+                    self.num_stopped_writers.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// Keep the first error of a background flush or compaction, or of a memtable freeze, and
+    /// wake up stopped writers to fail with it.
+    fn set_background_error(&self, e: anyhow::Error) {
+        self.background_error
+            .lock()
+            .get_or_insert_with(|| format!("{:#}", e));
+        let _write_stall = self.write_stall.lock();
+        self.write_resumed.notify_all();
+    }
+
+    fn check_background_error(&self) -> Result<()> {
+        match &*self.background_error.lock() {
+            Some(e) => Err(anyhow!("background error: {}", e)),
+            None => Ok(()),
+        }
+    }
 
+    /// Decide whether to hold back writes based on the shape of the LSM tree. Called whenever a
+    /// memtable is frozen or flushed, or an SST is compacted.
+    pub(crate) fn update_write_stall(&self) {
+        let snapshot = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        let num_l0_sstables = snapshot.l0_sstables.len();
+        let pending_compaction_bytes = self
+            .compaction_controller
+            .pending_compaction_bytes(&snapshot);
+        // Never stall writes on compaction if there is nothing to compact, e.g. without
+        // compaction, or they would be stalled forever. Flushes always catch up, unless they fail.
+        let state = if snapshot.imm_memtables.len() >= self.options.max_imm_memtables {
+            WriteStall::Stop
+        } else if pending_compaction_bytes == 0 {
+            WriteStall::Normal
+        } else if num_l0_sstables >= self.options.level0_stop_writes_trigger
+            || pending_compaction_bytes >= self.options.hard_pending_compaction_bytes_limit
+        {
+            WriteStall::Stop
+        } else if num_l0_sstables >= self.options.level0_slowdown_writes_trigger
+            || pending_compaction_bytes >= self.options.soft_pending_compaction_bytes_limit
+        {
+            WriteStall::Slowdown
+        } else {
+            WriteStall::Normal
+        };
+        *self.write_stall.lock() = state;
+        if state != WriteStall::Stop {
+            self.write_resumed.notify_all();
+        }
+    }
+
+    /// Write a group of batches to the current memtable, and return its approximate size.
+    fn commit_group(&self, group: &[(u64, WriteBatch, bool)]) -> Result<usize> {
+        let batches = group
+            .iter()
+            .map(|(_, batch, _)| batch.entries())
+            .collect::<Vec<_>>();
+        let sync = group.iter().any(|(_, _, sync)| *sync);
         let guard = self.inner.read();
-        guard.memtable.put(key, b"");
+        guard.memtable.put_batches(&batches, sync)?;
+        Ok(guard.memtable.approximate_size())
+    }
+
+    /// Freeze the current memtable if it has reached the size limit. If freezing fails, it is
+    /// recorded as a background error, so that later writes fail instead of retrying the freeze
+    /// and growing the memtable without bound.
+    fn try_freeze(&self, estimated_size: usize) {
+        if estimated_size < self.options.memtable_size_limit {
+            return;
+        }
+        let state_lock = self.state_lock.lock();
+        // Another thread may have frozen the memtable while we were waiting for the lock.
+        let need_freeze = {
+            let guard = self.inner.read();
+            guard.memtable.approximate_size() >= self.options.memtable_size_limit
+        };
+        if need_freeze {
+            if let Err(e) = self.force_freeze_memtable(&state_lock) {
+                self.set_background_error(e.context("failed to freeze memtable"));
+                return;
+            }
+            // It's fine if the flush thread has already been notified.
+            let _ = self.flush_notifier.try_send(());
+        }
+    }
+
+    /// Allocate the id of a new SST.
+    pub(crate) fn allocate_sst_id(&self) -> usize {
+        self.allocate_sst_id_locked(&self.state_lock.lock())
+    }
 
+    fn allocate_sst_id_locked(&self, _state_lock: &MutexGuard<'_, ()>) -> usize {
+        let mut guard = self.inner.write();
+        let mut snapshot = guard.as_ref().clone();
+        let id = snapshot.next_sst_id;
+        snapshot.next_sst_id += 1;
+        *guard = Arc::new(snapshot);
+        id
+    }
+
+    /// Move the current memtable to the immutable memtables, and start a new memtable.
+    fn force_freeze_memtable(&self, state_lock: &MutexGuard<'_, ()>) -> Result<()> {
+        // Take the id of the new memtable and record it before creating its WAL, so that the id is
+        // never reused, even if creating the WAL fails or the process crashes in between.
+        let memtable_id = self.allocate_sst_id_locked(state_lock);
+        self.manifest
+            .add_record(ManifestRecord::NewMemtable(memtable_id))?;
+
+        // Create the new memtable (and its WAL) before taking the write lock.
+        let memtable = Arc::new(MemTable::create_with_wal(
+            memtable_id,
+            self.path_of_wal(memtable_id),
+        )?);
+
+        // Taking the write lock waits for all in-flight writes to the old memtable. Readers either
+        // see the old memtable as the current one or as the latest immutable one, never neither.
+        let mut guard = self.inner.write();
+        // Swap the current memtable with a new one.
+        let mut snapshot = guard.as_ref().clone();
+        let frozen_memtable = std::mem::replace(&mut snapshot.memtable, memtable);
+        // Add the memtable to the immutable memtables.
+        snapshot.imm_memtables.push(frozen_memtable);
+        // Update the snapshot.
+        *guard = Arc::new(snapshot);
+        drop(guard);
+
+        self.update_write_stall();
         Ok(())
     }
 
-    fn path_of_sst(&self, id: usize) -> PathBuf {
-        self.path.join(format!("{:05}.sst", id))
+    fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.sst", id))
     }
 
-    /// Persist data to disk.
-    ///
-    /// In day 3: flush the current memtable to disk as L0 SST.
-    /// In day 6: call `fsync` on WAL.
-    pub fn sync(&self) -> Result<()> {
-        let _flush_lock = self.flush_lock.lock();
+    pub(crate) fn new_sst_builder(&self) -> SsTableBuilder {
+        let builder = SsTableBuilder::new(self.options.block_size)
+            .bloom_bits_per_key(self.options.bloom_bits_per_key);
+        match &self.options.prefix_extractor {
+            Some(prefix_extractor) => builder.prefix_extractor(prefix_extractor.clone()),
+            None => builder,
+        }
+    }
 
-        let flush_memtable;
-        let sst_id;
+    pub(crate) fn path_of_sst(&self, id: usize) -> PathBuf {
+        Self::path_of_sst_static(&self.path, id)
+    }
+
+    fn path_of_wal_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.wal", id))
+    }
+
+    fn path_of_wal(&self, id: usize) -> PathBuf {
+        Self::path_of_wal_static(&self.path, id)
+    }
 
-        // Move mutable memtable to immutable memtables.
+    /// Persist all writes to disk by calling `fsync` on the WALs of all memtables.
+    pub fn sync(&self) -> Result<()> {
+        let snapshot = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        for memtable in snapshot.imm_memtables.iter() {
+            memtable.sync_wal()?;
+        }
+        snapshot.memtable.sync_wal()
+    }
+
+    /// Freeze the current memtable (if not empty) and flush all immutable memtables.
+    pub fn force_flush(&self) -> Result<()> {
+        self.check_not_closed()?;
         {
-            let mut guard = self.inner.write();
-            // Swap the current memtable with a new one.
-            let mut snapshot = guard.as_ref().clone();
-            let memtable = std::mem::replace(&mut snapshot.memtable, Arc::new(MemTable::create()));
-            flush_memtable = memtable.clone();
-            sst_id = snapshot.next_sst_id;
-            // Add the memtable to the immutable memtables.
-            snapshot.imm_memtables.push(memtable);
-            // Update the snapshot.
-            *guard = Arc::new(snapshot);
+            let state_lock = self.state_lock.lock();
+            if !self.inner.read().memtable.is_empty() {
+                self.force_freeze_memtable(&state_lock)?;
+            }
+        }
+        self.flush_imm_memtables()
+    }
+
+    /// Flush all immutable memtables to disk, from earliest to latest.
+    fn flush_imm_memtables(&self) -> Result<()> {
+        let _flush_lock = self.flush_lock.lock();
+        loop {
+            let flush_memtable = {
+                let guard = self.inner.read();
+                match guard.imm_memtables.first() {
+                    Some(memtable) => memtable.clone(),
+                    None => break,
+                }
+            };
+            self.flush_memtable(&flush_memtable)?;
         }
+        Ok(())
+    }
+
+    /// Spawn a thread flushing immutable memtables in the background. The thread exits when
+    /// `stop_rx` receives a message or is disconnected.
+    fn spawn_flush_thread(
+        self: Arc<Self>,
+        flush_rx: Receiver<()>,
+        stop_rx: Receiver<()>,
+    ) -> Result<JoinHandle<()>> {
+        let handle = std::thread::Builder::new()
+            .name("mini-lsm-flush".to_string())
+            .spawn(move || {
+                // Also retry periodically, in case a previous flush failed.
+                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+                loop {
+                    crossbeam_channel::select! {
+                        recv(ticker) -> _ => {},
+                        recv(flush_rx) -> _ => {},
+                        recv(stop_rx) -> _ => return,
+                    }
+                    if let Err(e) = self.flush_imm_memtables() {
+                        self.set_background_error(e.context("flush failed"));
+                    }
+                }
+            })?;
+        Ok(handle)
+    }
 
-        // At this point, the old memtable should be disabled for write, and all write threads
-        // should be operating on the new memtable. We can safely flush the old memtable to
-        // disk.
+    /// Spawn a thread running compactions in the background, after each flush and periodically.
+    /// The thread exits when `stop_rx` receives a message or is disconnected.
+    fn spawn_compaction_thread(
+        self: Arc<Self>,
+        compaction_rx: Receiver<()>,
+        stop_rx: Receiver<()>,
+    ) -> Result<JoinHandle<()>> {
+        let handle = std::thread::Builder::new()
+            .name("mini-lsm-compaction".to_string())
+            .spawn(move || {
+                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+                loop {
+                    crossbeam_channel::select! {
+                        recv(ticker) -> _ => {},
+                        recv(compaction_rx) -> _ => {},
+                        recv(stop_rx) -> _ => return,
+                    }
+                    if let Err(e) = self.trigger_compaction() {
+                        self.set_background_error(e.context("compaction failed"));
+                    }
+                }
+            })?;
+        Ok(handle)
+    }
 
-        let mut builder = SsTableBuilder::new(4096);
+    /// Flush the earliest immutable memtable to disk as L0 SST, and remove its WAL.
+    fn flush_memtable(&self, flush_memtable: &Arc<MemTable>) -> Result<()> {
+        let sst_id = flush_memtable.id();
+        if flush_memtable.is_empty() {
+            // Nothing to flush (e.g. the writes were lost before reaching the WAL), just drop it.
+            {
+                let _state_lock = self.state_lock.lock();
+                let mut guard = self.inner.write();
+                let mut snapshot = guard.as_ref().clone();
+                snapshot.imm_memtables.remove(0);
+                *guard = Arc::new(snapshot);
+            }
+            self.update_write_stall();
+            std::fs::remove_file(self.path_of_wal(sst_id))?;
+            return Ok(());
+        }
+
+        let mut builder = self.new_sst_builder();
         flush_memtable.flush(&mut builder)?;
-        let sst = Arc::new(builder.build(
+        let sst = Arc::new(builder.build_with_rate_limiter(
             sst_id,
             Some(self.block_cache.clone()),
             self.path_of_sst(sst_id),
+            self.options.rate_limiter.as_deref(),
+            IoPriority::High,
         )?);
 
         // Add the flushed L0 table to the list.
         {
+            let _state_lock = self.state_lock.lock();
+            self.manifest.add_record(ManifestRecord::Flush(sst_id))?;
             let mut guard = self.inner.write();
             let mut snapshot = guard.as_ref().clone();
             // Remove the memtable from the immutable memtables.
-            snapshot.imm_memtables.pop();
+            let memtable = snapshot.imm_memtables.remove(0);
+            assert_eq!(memtable.id(), sst_id);
             // Add L0 table
             snapshot.l0_sstables.push(sst);
-            // Update SST ID
-            snapshot.next_sst_id += 1;
             // Update the snapshot.
             *guard = Arc::new(snapshot);
         }
 
+        self.update_write_stall();
+        // It's fine if the compaction thread has already been notified.
+        let _ = self.compaction_notifier.try_send(());
+
+        // The data is now in the SST, the WAL is no longer needed.
+        std::fs::remove_file(self.path_of_wal(sst_id))?;
+
         Ok(())
     }
 
@@ -198,28 +1031,65 @@ impl LsmStorage {
         }
         let memtable_iter = MergeIterator::create(memtable_iters);
 
-        let mut table_iters = Vec::new();
-        table_iters.reserve(snapshot.l0_sstables.len());
+        // SSTs without keys in the range are skipped. If all keys in the range have the same
+        // prefix, so are SSTs whose prefix filter rules out the prefix.
+        let prefix = self
+            .options
+            .prefix_extractor
+            .as_deref()
+            .and_then(|prefix_extractor| {
+                Some((
+                    prefix_extractor,
+                    scan_prefix(prefix_extractor, lower, upper)?,
+                ))
+            });
+        let may_have_keys = |table: &SsTable| {
+            range_overlap(lower, upper, table.first_key(), table.last_key())
+                && prefix.map_or(true, |(prefix_extractor, prefix)| {
+                    table.may_contain_prefix(prefix_extractor, prefix)
+                })
+        };
+
+        // L0 SSTs overlap, newer SSTs come first.
+        let mut l0_iters = Vec::new();
         for table in snapshot.l0_sstables.iter().rev() {
-            let iter = match lower {
-                Bound::Included(key) => {
-                    SsTableIterator::create_and_seek_to_key(table.clone(), key)?
-                }
-                Bound::Excluded(key) => {
-                    let mut iter = SsTableIterator::create_and_seek_to_key(table.clone(), key)?;
-                    if iter.is_valid() && iter.key() == key {
-                        iter.next()?;
+            if may_have_keys(table) {
+                let iter = match lower {
+                    Bound::Included(key) | Bound::Excluded(key) => {
+                        SsTableIterator::create_and_seek_to_key(table.clone(), key)?
                     }
-                    iter
+                    Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table.clone())?,
+                };
+                l0_iters.push(Box::new(skip_excluded_lower(iter, lower)?));
+            }
+        }
+        let l0_iter = MergeIterator::create(l0_iters);
+
+        // SSTs in a level don't overlap, they are read one after another.
+        let mut level_iters = Vec::new();
+        for level in &snapshot.levels {
+            let tables = level
+                .iter()
+                .filter(|table| may_have_keys(table))
+                .cloned()
+                .collect::<Vec<_>>();
+            if tables.is_empty() {
+                continue;
+            }
+            let iter = match lower {
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    SstConcatIterator::create_and_seek_to_key(tables, key)?
                 }
-                Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table.clone())?,
+                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(tables)?,
             };
-
-            table_iters.push(Box::new(iter));
+            level_iters.push(Box::new(skip_excluded_lower(iter, lower)?));
         }
-        let table_iter = MergeIterator::create(table_iters);
+        let level_iter = MergeIterator::create(level_iters);
 
-        let iter = TwoMergeIterator::create(memtable_iter, table_iter)?;
+        let iter = TwoMergeIterator::create(
+            TwoMergeIterator::create(memtable_iter, l0_iter)?,
+            level_iter,
+        )?;
 
         Ok(FusedIterator::new(LsmIterator::new(
             iter,
@@ -227,3 +1097,64 @@ impl LsmStorage {
         )?))
     }
 }
+
+/// Skip the key of an iterator seeked to an excluded lower bound.
+fn skip_excluded_lower<I: StorageIterator>(mut iter: I, lower: Bound<&[u8]>) -> Result<I> {
+    if let Bound::Excluded(key) = lower {
+        if iter.is_valid() && iter.key() == key {
+            iter.next()?;
+        }
+    }
+    Ok(iter)
+}
+
+/// The prefix shared by all keys in the range of a scan, if any.
+fn scan_prefix<'a>(
+    prefix_extractor: &dyn PrefixExtractor,
+    lower: Bound<&'a [u8]>,
+    upper: Bound<&[u8]>,
+) -> Option<&'a [u8]> {
+    let (Bound::Included(lower) | Bound::Excluded(lower)) = lower else {
+        return None;
+    };
+    let prefix = prefix_extractor.prefix(lower)?;
+    // Keys from the lower bound up to the smallest key greater than every key with the prefix,
+    // e.g. `tenant/1230` for `tenant/123/`, all have the prefix.
+    let successor = prefix_successor(prefix);
+    let shares_prefix = match (upper, successor.as_deref()) {
+        (_, None) => true,
+        (Bound::Included(upper), Some(successor)) => upper < successor,
+        (Bound::Excluded(upper), Some(successor)) => upper <= successor,
+        (Bound::Unbounded, Some(_)) => false,
+    };
+    shares_prefix.then_some(prefix)
+}
+
+/// The smallest key greater than every key starting with `prefix`, or `None` if there is no such
+/// key, i.e. the prefix is all `0xff` and every key after it starts with it.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let idx = prefix.iter().rposition(|&b| b != 0xff)?;
+    let mut successor = prefix[..=idx].to_vec();
+    successor[idx] += 1;
+    Some(successor)
+}
+
+/// Whether the key range `[first_key, last_key]` of an SST overlaps with the range of a scan.
+pub(crate) fn range_overlap(
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    first_key: &[u8],
+    last_key: &[u8],
+) -> bool {
+    match lower {
+        Bound::Included(key) if key > last_key => return false,
+        Bound::Excluded(key) if key >= last_key => return false,
+        _ => {}
+    }
+    match upper {
+        Bound::Included(key) if key < first_key => return false,
+        Bound::Excluded(key) if key <= first_key => return false,
+        _ => {}
+    }
+    true
+}
@@ -0,0 +1,166 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut};
+use parking_lot::Mutex;
+
+use crate::table::sync_parent_dir;
+
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// A version edit, i.e. a change to the structure of the LSM tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManifestRecord {
+    /// A new memtable is created, logging to the WAL with the given id.
+    NewMemtable(usize),
+    /// The earliest immutable memtable is flushed to the L0 SST with the same id.
+    Flush(usize),
+    /// A compaction removes SSTs `removed` from the tree, and adds SSTs `added` to `level`.
+    Compaction {
+        level: usize,
+        removed: Vec<usize>,
+        added: Vec<usize>,
+    },
+}
+
+const RECORD_NEW_MEMTABLE: u8 = 0;
+const RECORD_FLUSH: u8 = 1;
+const RECORD_COMPACTION: u8 = 2;
+
+impl ManifestRecord {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            ManifestRecord::NewMemtable(id) => {
+                buf.put_u8(RECORD_NEW_MEMTABLE);
+                buf.put_u64(*id as u64);
+            }
+            ManifestRecord::Flush(id) => {
+                buf.put_u8(RECORD_FLUSH);
+                buf.put_u64(*id as u64);
+            }
+            ManifestRecord::Compaction {
+                level,
+                removed,
+                added,
+            } => {
+                buf.put_u8(RECORD_COMPACTION);
+                buf.put_u64(*level as u64);
+                for ids in [removed, added] {
+                    buf.put_u32(ids.len() as u32);
+                    for id in ids {
+                        buf.put_u64(*id as u64);
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        let record = match buf.get_u8() {
+            RECORD_NEW_MEMTABLE => ManifestRecord::NewMemtable(buf.get_u64() as usize),
+            RECORD_FLUSH => ManifestRecord::Flush(buf.get_u64() as usize),
+            RECORD_COMPACTION => {
+                let level = buf.get_u64() as usize;
+                let mut decode_ids = || {
+                    let len = buf.get_u32() as usize;
+                    (0..len).map(|_| buf.get_u64() as usize).collect()
+                };
+                let removed = decode_ids();
+                let added = decode_ids();
+                ManifestRecord::Compaction {
+                    level,
+                    removed,
+                    added,
+                }
+            }
+            tag => bail!("unknown manifest record type {}", tag),
+        };
+        Ok(record)
+    }
+}
+
+/// The manifest is an append-only log of [`ManifestRecord`]s. Replaying it from the start rebuilds
+/// the structure of the LSM tree.
+///
+/// Each record is encoded as:
+///
+/// ```plaintext
+/// | len (u32) | record | checksum (u32) |
+/// ```
+pub struct Manifest {
+    file: Mutex<File>,
+}
+
+impl Manifest {
+    /// Create a new manifest file. Fails if the file already exists. The directory is synced, so
+    /// that the file itself survives a crash.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to create manifest {}", path.as_ref().display()))?;
+        sync_parent_dir(path.as_ref())?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Open an existing manifest for appending and return all records in it. A torn record at the
+    /// end of the manifest is discarded.
+    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to recover manifest {}", path.as_ref().display()))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut rbuf = &buf[..];
+        let mut records = Vec::new();
+        while rbuf.remaining() >= SIZEOF_U32 {
+            let len = (&rbuf[..SIZEOF_U32]).get_u32() as usize;
+            if rbuf.remaining() < SIZEOF_U32 * 2 + len {
+                break;
+            }
+            let record = &rbuf[SIZEOF_U32..SIZEOF_U32 + len];
+            let checksum = (&rbuf[SIZEOF_U32 + len..]).get_u32();
+            if checksum != crc32fast::hash(record) {
+                break;
+            }
+            records.push(ManifestRecord::decode(record)?);
+            rbuf.advance(SIZEOF_U32 * 2 + len);
+        }
+        let valid_len = buf.len() - rbuf.len();
+        if valid_len != buf.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        Ok((
+            Self {
+                file: Mutex::new(file),
+            },
+            records,
+        ))
+    }
+
+    /// Append a record to the manifest and persist it with `fsync`.
+    pub fn add_record(&self, record: ManifestRecord) -> Result<()> {
+        let mut encoded = Vec::new();
+        record.encode(&mut encoded);
+        let mut buf = Vec::with_capacity(encoded.len() + SIZEOF_U32 * 2);
+        buf.put_u32(encoded.len() as u32);
+        buf.put_slice(&encoded);
+        buf.put_u32(crc32fast::hash(&encoded));
+        let mut file = self.file.lock();
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,68 @@
+use std::fs::OpenOptions;
+
+use tempfile::tempdir;
+
+use super::{Manifest, ManifestRecord};
+
+fn records() -> Vec<ManifestRecord> {
+    vec![
+        ManifestRecord::NewMemtable(1),
+        ManifestRecord::NewMemtable(2),
+        ManifestRecord::Flush(1),
+        ManifestRecord::Compaction {
+            level: 1,
+            removed: vec![1],
+            added: vec![3, 4],
+        },
+        ManifestRecord::Compaction {
+            level: 2,
+            removed: vec![],
+            added: vec![],
+        },
+    ]
+}
+
+#[test]
+fn test_manifest_recover() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("MANIFEST");
+    {
+        let manifest = Manifest::create(&path).unwrap();
+        for record in records() {
+            manifest.add_record(record).unwrap();
+        }
+    }
+    let (manifest, recovered) = Manifest::recover(&path).unwrap();
+    assert_eq!(recovered, records());
+    manifest.add_record(ManifestRecord::Flush(2)).unwrap();
+    let (_, recovered) = Manifest::recover(&path).unwrap();
+    assert_eq!(recovered.len(), records().len() + 1);
+    assert_eq!(recovered.last(), Some(&ManifestRecord::Flush(2)));
+}
+
+#[test]
+fn test_manifest_recover_torn_tail() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("MANIFEST");
+    {
+        let manifest = Manifest::create(&path).unwrap();
+        for record in records() {
+            manifest.add_record(record).unwrap();
+        }
+    }
+    let len = std::fs::metadata(&path).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - 1)
+        .unwrap();
+    {
+        let (manifest, recovered) = Manifest::recover(&path).unwrap();
+        assert_eq!(recovered[..], records()[..records().len() - 1]);
+        manifest.add_record(ManifestRecord::Flush(2)).unwrap();
+    }
+    let (_, recovered) = Manifest::recover(&path).unwrap();
+    assert_eq!(recovered.len(), records().len());
+    assert_eq!(recovered.last(), Some(&ManifestRecord::Flush(2)));
+}
@@ -1,18 +1,37 @@
+use std::cmp::Reverse;
 use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use anyhow::Result;
 use bytes::Bytes;
-use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
+use parking_lot::Mutex;
 
 use crate::iterators::StorageIterator;
 use crate::table::SsTableBuilder;
+use crate::wal::Wal;
 
-/// A basic mem-table based on crossbeam-skiplist
+/// A key in the skiplist, tagged with the sequence number of the batch that wrote it. Versions of
+/// the same key are ordered from latest to earliest.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VersionedKey(Bytes, Reverse<u64>);
+
+/// A basic mem-table based on crossbeam-skiplist.
+///
+/// Each batch written to the mem-table gets a new sequence number, and becomes visible to readers
+/// only after all of its entries are inserted, so that no reader sees part of a batch.
 pub struct MemTable {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<SkipMap<VersionedKey, Bytes>>,
+    wal: Option<Wal>,
+    id: usize,
+    approximate_size: AtomicUsize,
+    /// The sequence number of the latest batch visible to readers.
+    visible_seq: AtomicU64,
+    /// Serializes writers, so that batches become visible in the order of their sequence numbers.
+    write_lock: Mutex<()>,
 }
 
 pub(crate) fn map_bound(bound: Bound<&[u8]>) -> Bound<Bytes> {
@@ -23,66 +42,210 @@ pub(crate) fn map_bound(bound: Bound<&[u8]>) -> Bound<Bytes> {
     }
 }
 
+fn map_lower_bound(bound: Bound<&[u8]>) -> Bound<VersionedKey> {
+    match bound {
+        Bound::Included(x) => {
+            Bound::Included(VersionedKey(Bytes::copy_from_slice(x), Reverse(u64::MAX)))
+        }
+        Bound::Excluded(x) => Bound::Excluded(VersionedKey(Bytes::copy_from_slice(x), Reverse(0))),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn map_upper_bound(bound: Bound<&[u8]>) -> Bound<VersionedKey> {
+    match bound {
+        Bound::Included(x) => Bound::Included(VersionedKey(Bytes::copy_from_slice(x), Reverse(0))),
+        Bound::Excluded(x) => {
+            Bound::Excluded(VersionedKey(Bytes::copy_from_slice(x), Reverse(u64::MAX)))
+        }
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
 impl MemTable {
-    /// Create a new mem-table.
-    pub fn create() -> Self {
+    fn new(id: usize, wal: Option<Wal>) -> Self {
         Self {
             map: Arc::new(SkipMap::new()),
+            wal,
+            id,
+            approximate_size: AtomicUsize::new(0),
+            visible_seq: AtomicU64::new(0),
+            write_lock: Mutex::new(()),
         }
     }
 
+    /// Create a new mem-table without a WAL.
+    pub fn create(id: usize) -> Self {
+        Self::new(id, None)
+    }
+
+    /// Create a new mem-table, logging every write to a new WAL at `path`.
+    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(id, Some(Wal::create(path)?)))
+    }
+
+    /// Rebuild a mem-table from the WAL at `path`.
+    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+        let (wal, batches) = Wal::recover(path)?;
+        let memtable = Self::new(id, Some(wal));
+        for batch in batches {
+            memtable.apply_batch(&batch);
+        }
+        Ok(memtable)
+    }
+
     /// Get a value by key.
     pub fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.map.get(key).map(|e| e.value().clone())
+        let read_seq = self.visible_seq.load(Ordering::Acquire);
+        // The latest version no newer than `read_seq`.
+        let lower = VersionedKey(Bytes::copy_from_slice(key), Reverse(read_seq));
+        self.map
+            .range(lower..)
+            .next()
+            .filter(|entry| entry.key().0 == key)
+            .map(|entry| entry.value().clone())
     }
 
-    /// Put a key-value pair into the mem-table.
-    pub fn put(&self, key: &[u8], value: &[u8]) {
-        self.map
-            .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+    /// Put a key-value pair into the mem-table. The write is logged to the WAL first, if any.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_batch(&[(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value))])
     }
 
-    /// Get an iterator over a range of keys.
+    /// Put a batch of key-value pairs into the mem-table atomically. The batch is logged to the
+    /// WAL as a single record first, if any. If a key occurs multiple times in the batch, the last
+    /// one wins.
+    pub fn put_batch(&self, batch: &[(Bytes, Bytes)]) -> Result<()> {
+        self.put_batches(&[batch], false)
+    }
+
+    /// Put multiple batches into the mem-table in order, each of them atomically. All batches are
+    /// logged to the WAL in a single write, followed by one `fsync` if `sync` is set.
+    pub fn put_batches(&self, batches: &[&[(Bytes, Bytes)]], sync: bool) -> Result<()> {
+        let _write_lock = self.write_lock.lock();
+        if let Some(ref wal) = self.wal {
+            wal.put_batches(batches)?;
+            if sync {
+                wal.sync()?;
+            }
+        }
+        for batch in batches {
+            self.apply_batch(batch);
+        }
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: &[(Bytes, Bytes)]) {
+        let seq = self.visible_seq.load(Ordering::Relaxed) + 1;
+        let mut size = 0;
+        for (key, value) in batch {
+            size += key.len() + value.len();
+            self.map
+                .insert(VersionedKey(key.clone(), Reverse(seq)), value.clone());
+        }
+        self.approximate_size.fetch_add(size, Ordering::Relaxed);
+        // Publish the whole batch to readers at once.
+        self.visible_seq.store(seq, Ordering::Release);
+    }
+
+    /// Flush the WAL to disk with `fsync`.
+    pub fn sync_wal(&self) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Number of times the WAL has been flushed to disk with `fsync`.
+    #[cfg(test)]
+    pub(crate) fn num_wal_syncs(&self) -> usize {
+        self.wal.as_ref().map_or(0, Wal::num_syncs)
+    }
+
+    /// Get an iterator over a range of keys. Batches written after the iterator is created are
+    /// not visible to it.
     pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
-        let (lower, upper) = (map_bound(lower), map_bound(upper));
+        let (lower, upper) = (map_lower_bound(lower), map_upper_bound(upper));
         let mut iter = MemTableIteratorBuilder {
             map: self.map.clone(),
             iter_builder: |map| map.range((lower, upper)),
             item: (Bytes::from_static(&[]), Bytes::from_static(&[])),
+            read_seq: self.visible_seq.load(Ordering::Acquire),
         }
         .build();
-        let entry = iter.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
-        iter.with_mut(|x| *x.item = entry);
+        iter.move_to_next_visible();
         iter
     }
 
-    /// Flush the mem-table to SSTable.
+    /// Flush the mem-table to SSTable. The mem-table should no longer be written to.
     pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
+        let mut prev_key = Bytes::new();
         for entry in self.map.iter() {
-            builder.add(&entry.key()[..], &entry.value()[..]);
+            let VersionedKey(key, _) = entry.key();
+            // Only the latest version of each key is flushed.
+            if *key == prev_key {
+                continue;
+            }
+            builder.add(&key[..], &entry.value()[..]);
+            prev_key = key.clone();
         }
         Ok(())
     }
+
+    /// Get the id of the mem-table. The SST flushed from it has the same id.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Get the approximate size of the data written to the mem-table, in bytes. Overwritten
+    /// entries are still counted.
+    pub fn approximate_size(&self) -> usize {
+        self.approximate_size.load(Ordering::Relaxed)
+    }
+
+    /// Check if the mem-table contains no key.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
 }
 
-type SkipMapRangeIter<'a> =
-    crossbeam_skiplist::map::Range<'a, Bytes, (Bound<Bytes>, Bound<Bytes>), Bytes, Bytes>;
+type SkipMapRangeIter<'a> = crossbeam_skiplist::map::Range<
+    'a,
+    VersionedKey,
+    (Bound<VersionedKey>, Bound<VersionedKey>),
+    VersionedKey,
+    Bytes,
+>;
 
 /// An iterator over a range of `SkipMap`.
 #[self_referencing]
 pub struct MemTableIterator {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<SkipMap<VersionedKey, Bytes>>,
     #[borrows(map)]
     #[not_covariant]
     iter: SkipMapRangeIter<'this>,
     item: (Bytes, Bytes),
+    /// Only batches with a sequence number no larger than this are visible.
+    read_seq: u64,
 }
 
 impl MemTableIterator {
-    fn entry_to_item(entry: Option<Entry<'_, Bytes, Bytes>>) -> (Bytes, Bytes) {
-        entry
-            .map(|x| (x.key().clone(), x.value().clone()))
-            .unwrap_or_else(|| (Bytes::from_static(&[]), Bytes::from_static(&[])))
+    /// Move to the latest visible version of the next key.
+    fn move_to_next_visible(&mut self) {
+        self.with_mut(|x| {
+            let read_seq = *x.read_seq;
+            let mut item = (Bytes::from_static(&[]), Bytes::from_static(&[]));
+            for entry in x.iter {
+                let VersionedKey(key, Reverse(seq)) = entry.key();
+                // Skip batches written after the iterator is created, and earlier versions of the
+                // current key.
+                if *seq > read_seq || *key == x.item.0 {
+                    continue;
+                }
+                item = (key.clone(), entry.value().clone());
+                break;
+            }
+            *x.item = item;
+        });
     }
 }
 
@@ -100,8 +263,7 @@ impl StorageIterator for MemTableIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
-        self.with_mut(|x| *x.item = entry);
+        self.move_to_next_visible();
         Ok(())
     }
 }
@@ -6,10 +6,10 @@ use crate::table::{SsTableBuilder, SsTableIterator};
 
 #[test]
 fn test_memtable_get() {
-    let memtable = MemTable::create();
-    memtable.put(b"key1", b"value1");
-    memtable.put(b"key2", b"value2");
-    memtable.put(b"key3", b"value3");
+    let memtable = MemTable::create(0);
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key3", b"value3").unwrap();
     assert_eq!(&memtable.get(b"key1").unwrap()[..], b"value1");
     assert_eq!(&memtable.get(b"key2").unwrap()[..], b"value2");
     assert_eq!(&memtable.get(b"key3").unwrap()[..], b"value3");
@@ -17,13 +17,13 @@ fn test_memtable_get() {
 
 #[test]
 fn test_memtable_overwrite() {
-    let memtable = MemTable::create();
-    memtable.put(b"key1", b"value1");
-    memtable.put(b"key2", b"value2");
-    memtable.put(b"key3", b"value3");
-    memtable.put(b"key1", b"value11");
-    memtable.put(b"key2", b"value22");
-    memtable.put(b"key3", b"value33");
+    let memtable = MemTable::create(0);
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key3", b"value3").unwrap();
+    memtable.put(b"key1", b"value11").unwrap();
+    memtable.put(b"key2", b"value22").unwrap();
+    memtable.put(b"key3", b"value33").unwrap();
     assert_eq!(&memtable.get(b"key1").unwrap()[..], b"value11");
     assert_eq!(&memtable.get(b"key2").unwrap()[..], b"value22");
     assert_eq!(&memtable.get(b"key3").unwrap()[..], b"value33");
@@ -31,10 +31,10 @@ fn test_memtable_overwrite() {
 
 #[test]
 fn test_memtable_flush() {
-    let memtable = MemTable::create();
-    memtable.put(b"key1", b"value1");
-    memtable.put(b"key2", b"value2");
-    memtable.put(b"key3", b"value3");
+    let memtable = MemTable::create(0);
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key3", b"value3").unwrap();
     let mut builder = SsTableBuilder::new(128);
     memtable.flush(&mut builder).unwrap();
     let dir = tempdir().unwrap();
@@ -55,10 +55,10 @@ fn test_memtable_flush() {
 #[test]
 fn test_memtable_iter() {
     use std::ops::Bound;
-    let memtable = MemTable::create();
-    memtable.put(b"key1", b"value1");
-    memtable.put(b"key2", b"value2");
-    memtable.put(b"key3", b"value3");
+    let memtable = MemTable::create(0);
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key3", b"value3").unwrap();
 
     {
         let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
@@ -93,3 +93,40 @@ fn test_memtable_iter() {
         assert!(!iter.is_valid());
     }
 }
+
+#[test]
+fn test_memtable_approximate_size() {
+    let memtable = MemTable::create(0);
+    assert_eq!(memtable.approximate_size(), 0);
+    memtable.put(b"key1", b"value1").unwrap();
+    assert_eq!(memtable.approximate_size(), 10);
+    memtable.put(b"key1", b"").unwrap();
+    assert_eq!(memtable.approximate_size(), 14);
+}
+
+#[test]
+fn test_memtable_put_batch() {
+    use bytes::Bytes;
+    let memtable = MemTable::create(0);
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable
+        .put_batch(&[
+            (Bytes::from("key1"), Bytes::from("value11")),
+            (Bytes::from("key2"), Bytes::from("value2")),
+            (Bytes::from("key2"), Bytes::from("value22")),
+        ])
+        .unwrap();
+    assert_eq!(&memtable.get(b"key1").unwrap()[..], b"value11");
+    assert_eq!(&memtable.get(b"key2").unwrap()[..], b"value22");
+    let mut iter = memtable.scan(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded);
+    // Batches written after the iterator is created are not visible.
+    memtable.put(b"key0", b"value0").unwrap();
+    memtable.put(b"key1", b"value111").unwrap();
+    assert_eq!(iter.key(), b"key1");
+    assert_eq!(iter.value(), b"value11");
+    iter.next().unwrap();
+    assert_eq!(iter.key(), b"key2");
+    assert_eq!(iter.value(), b"value22");
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+}
@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// Priority of an I/O request. Low-priority requests wait while high-priority ones are waiting
+/// for tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// e.g. flushes, which hold back writes when they fall behind.
+    High,
+    /// e.g. compactions.
+    Low,
+}
+
+/// Tokens are refilled continuously, but at most this much worth of them are saved up.
+const REFILL_PERIOD: Duration = Duration::from_millis(100);
+
+/// A token-bucket rate limiter for background I/O. Each byte read or written takes a token, and
+/// tokens are refilled at a rate of `bytes_per_second`. The rate can be changed at any time, `0`
+/// means unlimited.
+pub struct RateLimiter {
+    state: Mutex<RateLimiterState>,
+    /// Notified when high-priority requests are done, or the rate is changed.
+    state_changed: Condvar,
+}
+
+struct RateLimiterState {
+    bytes_per_second: u64,
+    available: f64,
+    last_refill: Instant,
+    num_high_priority_waiters: usize,
+    total_bytes_through: u64,
+}
+
+impl RateLimiterState {
+    fn new(bytes_per_second: u64, now: Instant) -> Self {
+        Self {
+            bytes_per_second,
+            available: 0.0,
+            last_refill: now,
+            num_high_priority_waiters: 0,
+            total_bytes_through: 0,
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.bytes_per_second as f64 * REFILL_PERIOD.as_secs_f64()).max(1.0)
+    }
+
+    /// Add the tokens refilled since the last refill, up to `now`.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.available =
+            (self.available + elapsed * self.bytes_per_second as f64).min(self.capacity());
+        self.last_refill = self.last_refill.max(now);
+    }
+
+    /// Take up to `remaining` tokens at `now` for a request at `priority`. Returns the number of
+    /// tokens taken, or how long to wait before trying again if none can be taken.
+    fn take(
+        &mut self,
+        remaining: f64,
+        priority: IoPriority,
+        now: Instant,
+    ) -> Result<f64, Duration> {
+        self.refill(now);
+        let can_take = priority == IoPriority::High || self.num_high_priority_waiters == 0;
+        if can_take && self.available >= 1.0 {
+            let taken = remaining.min(self.available.floor());
+            self.available -= taken;
+            return Ok(taken);
+        }
+        if !can_take {
+            return Err(REFILL_PERIOD);
+        }
+        let needed = remaining.min(self.capacity()) - self.available;
+        Err(
+            Duration::from_secs_f64(needed / self.bytes_per_second as f64)
+                .clamp(Duration::from_millis(1), REFILL_PERIOD),
+        )
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            state: Mutex::new(RateLimiterState::new(bytes_per_second, Instant::now())),
+            state_changed: Condvar::new(),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.state.lock().bytes_per_second
+    }
+
+    /// Total number of bytes requested so far, including those requested while unlimited.
+    pub fn total_bytes_through(&self) -> u64 {
+        self.state.lock().total_bytes_through
+    }
+
+    /// Change the rate. Requests that are waiting pick up the new rate right away.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut state = self.state.lock();
+        state.refill(Instant::now());
+        state.bytes_per_second = bytes_per_second;
+        state.available = state.available.min(state.capacity());
+        self.state_changed.notify_all();
+    }
+
+    /// Block until `bytes` tokens are taken. Requests larger than the bucket are served in
+    /// multiple parts.
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        let mut remaining = bytes as f64;
+        let mut state = self.state.lock();
+        state.total_bytes_through += bytes as u64;
+        if priority == IoPriority::High {
+            state.num_high_priority_waiters += 1;
+        }
+        while remaining > 0.0 && state.bytes_per_second != 0 {
+            match state.take(remaining, priority, Instant::now()) {
+                Ok(taken) => remaining -= taken,
+                Err(wait) => {
+                    self.state_changed.wait_for(&mut state, wait);
+                }
+            }
+        }
+        if priority == IoPriority::High {
+            state.num_high_priority_waiters -= 1;
+            self.state_changed.notify_all();
+        }
+    }
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("bytes_per_second", &self.bytes_per_second())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{IoPriority, RateLimiter, RateLimiterState, REFILL_PERIOD};
+
+/// Serve a request of `bytes` at `priority` from `state`, advancing the clock `now` by each wait.
+fn request_at(state: &mut RateLimiterState, bytes: usize, priority: IoPriority, now: &mut Instant) {
+    let mut remaining = bytes as f64;
+    while remaining > 0.0 {
+        match state.take(remaining, priority, *now) {
+            Ok(taken) => remaining -= taken,
+            Err(wait) => *now += wait,
+        }
+    }
+}
+
+#[test]
+fn test_rate_limiter_unlimited() {
+    let limiter = RateLimiter::new(0);
+    limiter.request(1 << 30, IoPriority::Low);
+    limiter.request(1 << 30, IoPriority::High);
+    assert_eq!(limiter.total_bytes_through(), 2 << 30);
+}
+
+#[test]
+fn test_rate_limiter_rate() {
+    let start = Instant::now();
+    let mut now = start;
+    let mut state = RateLimiterState::new(100 << 10, start);
+    for _ in 0..10 {
+        request_at(&mut state, 5 << 10, IoPriority::Low, &mut now);
+    }
+    // 50KB at 100KB/s.
+    let elapsed = now - start;
+    assert!(
+        elapsed >= Duration::from_millis(499) && elapsed <= Duration::from_millis(501),
+        "{:?}",
+        elapsed
+    );
+}
+
+#[test]
+fn test_rate_limiter_refill() {
+    let start = Instant::now();
+    let mut state = RateLimiterState::new(100 << 10, start);
+    // Nothing is available at first. 5KB take 50ms to refill.
+    assert_eq!(
+        state.take((5 << 10) as f64, IoPriority::Low, start),
+        Err(Duration::from_millis(50))
+    );
+    assert_eq!(
+        state.take(
+            (5 << 10) as f64,
+            IoPriority::Low,
+            start + Duration::from_millis(50)
+        ),
+        Ok((5 << 10) as f64)
+    );
+    // At most one refill period worth of tokens is saved up, i.e. 10KB.
+    assert_eq!(
+        state.take(
+            (1 << 20) as f64,
+            IoPriority::Low,
+            start + Duration::from_secs(10)
+        ),
+        Ok((10 << 10) as f64)
+    );
+}
+
+#[test]
+fn test_rate_limiter_set_rate() {
+    let limiter = Arc::new(RateLimiter::new(1));
+    let handle = {
+        let limiter = limiter.clone();
+        std::thread::spawn(move || limiter.request(1 << 20, IoPriority::High))
+    };
+    // At one byte per second, the request only finishes once the limit is lifted.
+    limiter.set_bytes_per_second(0);
+    assert_eq!(limiter.bytes_per_second(), 0);
+    handle.join().unwrap();
+    assert_eq!(limiter.total_bytes_through(), 1 << 20);
+}
+
+#[test]
+fn test_rate_limiter_priority() {
+    let start = Instant::now();
+    let mut state = RateLimiterState::new(100 << 10, start);
+    let now = start + REFILL_PERIOD;
+    // Low-priority requests wait while a high-priority one is waiting, even if tokens are
+    // available.
+    state.num_high_priority_waiters = 1;
+    assert_eq!(
+        state.take((1 << 10) as f64, IoPriority::Low, now),
+        Err(REFILL_PERIOD)
+    );
+    assert_eq!(
+        state.take((1 << 10) as f64, IoPriority::High, now),
+        Ok((1 << 10) as f64)
+    );
+    state.num_high_priority_waiters = 0;
+    assert_eq!(
+        state.take((1 << 10) as f64, IoPriority::Low, now),
+        Ok((1 << 10) as f64)
+    );
+}
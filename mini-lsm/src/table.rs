@@ -1,17 +1,26 @@
+mod bloom;
 mod builder;
+mod filter;
+mod footer;
 mod iterator;
 
 use std::fs::File;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 pub use builder::SsTableBuilder;
 use bytes::{Buf, BufMut, Bytes};
+use filter::Filters;
+pub use filter::{FixedLengthPrefixExtractor, PrefixExtractor};
+use footer::Footer;
 pub use iterator::SsTableIterator;
 
-use crate::block::Block;
+use crate::block::{Block, BlockIterator};
 use crate::lsm_storage::BlockCache;
+use crate::rate_limiter::{IoPriority, RateLimiter};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
@@ -57,6 +66,31 @@ impl BlockMeta {
     }
 }
 
+/// Rate-limited writes are split into chunks of this size, in bytes.
+const RATE_LIMITED_WRITE_SIZE: usize = 64 << 10;
+
+/// Extension of the temporary files that SSTs are written to before being renamed into place.
+pub const TEMP_FILE_EXTENSION: &str = "tmp";
+
+fn temp_path_of(path: &Path) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".");
+    tmp_path.push(TEMP_FILE_EXTENSION);
+    tmp_path.into()
+}
+
+/// Sync the directory containing `path` with `fsync`, so that a file created or renamed at
+/// `path` survives a crash.
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("failed to sync directory {}", dir.display()))
+}
+
 /// A file object.
 ///
 /// Before day 4, it should look like:
@@ -95,26 +129,124 @@ impl FileObject {
         self.1
     }
 
+    /// When the file was last modified. SSTs are never modified after they are written.
+    pub fn modified(&self) -> Result<SystemTime> {
+        Ok(self.0.metadata()?.modified()?)
+    }
+
     /// Create a new file object (day 2) and write the file to the disk (day 4).
+    ///
+    /// The data is written to a temporary file first, which is persisted with `fsync` and then
+    /// renamed to `path`, so that a crash never leaves a partially written file at `path`. The
+    /// directory is synced as well to persist the rename.
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
+        Self::create_with_rate_limiter(path, data, None, IoPriority::High)
+    }
+
+    /// Like [`FileObject::create`], but the data is written in chunks, each taking tokens from
+    /// `rate_limiter` at `priority`.
+    pub fn create_with_rate_limiter(
+        path: &Path,
+        data: Vec<u8>,
+        rate_limiter: Option<&RateLimiter>,
+        priority: IoPriority,
+    ) -> Result<Self> {
+        let tmp_path = temp_path_of(path);
+        {
+            let mut file = File::create(&tmp_path)
+                .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+            match rate_limiter {
+                Some(rate_limiter) => {
+                    for chunk in data.chunks(RATE_LIMITED_WRITE_SIZE) {
+                        rate_limiter.request(chunk.len(), priority);
+                        file.write_all(chunk)?;
+                    }
+                }
+                None => file.write_all(&data)?,
+            }
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+        sync_parent_dir(path)?;
         Ok(FileObject(
             File::options().read(true).write(false).open(path)?,
             data.len() as u64,
         ))
     }
 
-    pub fn open(_path: &Path) -> Result<Self> {
-        unimplemented!()
+    /// Open an existing file object from the disk.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(false)
+            .open(path)
+            .with_context(|| format!("failed to open SST {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(FileObject(file, size))
+    }
+}
+
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// Returned when data read from an SST does not match its checksum.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstCorruption {
+    pub sst_id: usize,
+    pub section: SstSection,
+}
+
+/// A checksummed section of an SST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SstSection {
+    /// The data block with the given index.
+    Block(usize),
+    BlockMeta,
+    Filter,
+}
+
+impl std::fmt::Display for SstCorruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SST {} is corrupted: checksum mismatch in ", self.sst_id)?;
+        match self.section {
+            SstSection::Block(block_idx) => write!(f, "block {}", block_idx),
+            SstSection::BlockMeta => write!(f, "block meta"),
+            SstSection::Filter => write!(f, "filter"),
+        }
     }
 }
 
+impl std::error::Error for SstCorruption {}
+
+/// Split `data` into the payload and the checksum appended to it, and verify the checksum.
+fn verify_checksum(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < SIZEOF_U32 {
+        return None;
+    }
+    let (payload, mut checksum) = data.split_at(data.len() - SIZEOF_U32);
+    if checksum.get_u32() != crc32fast::hash(payload) {
+        return None;
+    }
+    Some(payload)
+}
+
+/// An SSTable. Each data block and the block meta are followed by a crc32 checksum, and the file
+/// ends with a [`Footer`] locating the sections:
+///
+/// ```plaintext
+/// | block | checksum (u32) | ... | block meta | checksum (u32) | filter | properties | footer |
+/// ```
+///
+/// The filter section holds the bloom filters of the SST, see [`Filters`].
 pub struct SsTable {
     file: FileObject,
     block_metas: Vec<BlockMeta>,
     block_meta_offset: usize,
     id: usize,
     block_cache: Option<Arc<BlockCache>>,
+    first_key: Bytes,
+    last_key: Bytes,
+    creation_time: SystemTime,
+    filters: Filters,
 }
 
 impl SsTable {
@@ -125,41 +257,109 @@ impl SsTable {
 
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
-        let len = file.size();
-        let raw_meta_offset = file.read(len - 4, 4)?;
-        let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
-        let raw_meta = file.read(block_meta_offset, len - 4 - block_meta_offset)?;
-        Ok(Self {
+        let footer = Footer::read(id, &file)?;
+        let raw_meta = file.read(
+            footer.meta_offset as u64,
+            (footer.filter_offset - footer.meta_offset) as u64,
+        )?;
+        let raw_meta = verify_checksum(&raw_meta).ok_or(SstCorruption {
+            sst_id: id,
+            section: SstSection::BlockMeta,
+        })?;
+        let block_metas = BlockMeta::decode_block_meta(raw_meta);
+        let filters = if footer.properties_offset > footer.filter_offset {
+            let raw_filter = file.read(
+                footer.filter_offset as u64,
+                (footer.properties_offset - footer.filter_offset) as u64,
+            )?;
+            let raw_filter = verify_checksum(&raw_filter).ok_or(SstCorruption {
+                sst_id: id,
+                section: SstSection::Filter,
+            })?;
+            Filters::decode(footer.version, raw_filter)?
+        } else {
+            Filters::default()
+        };
+        let first_key = block_metas
+            .first()
+            .map(|meta| meta.first_key.clone())
+            .unwrap_or_default();
+        let creation_time = file.modified()?;
+        let mut table = Self {
             file,
-            block_metas: BlockMeta::decode_block_meta(&raw_meta[..]),
-            block_meta_offset: block_meta_offset as usize,
+            block_metas,
+            block_meta_offset: footer.meta_offset,
             id,
             block_cache,
-        })
+            first_key,
+            last_key: Bytes::new(),
+            creation_time,
+            filters,
+        };
+        // The last key is not stored in the block meta, find it in the last block.
+        if let Some(last_block_idx) = table.num_of_blocks().checked_sub(1) {
+            let mut iter =
+                BlockIterator::create_and_seek_to_first(table.read_block(last_block_idx)?);
+            while iter.is_valid() {
+                table.last_key = Bytes::copy_from_slice(iter.key());
+                iter.next();
+            }
+        }
+        Ok(table)
     }
 
     /// Read a block from the disk.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.read_block_with_rate_limiter(block_idx, None)
+    }
+
+    /// Read a block from the disk, taking tokens from `rate_limiter` at low priority.
+    pub fn read_block_with_rate_limiter(
+        &self,
+        block_idx: usize,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Arc<Block>> {
         let offset = self.block_metas[block_idx].offset;
         let offset_end = self
             .block_metas
             .get(block_idx + 1)
             .map_or(self.block_meta_offset, |x| x.offset);
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.request(offset_end - offset, IoPriority::Low);
+        }
         let block_data = self
             .file
             .read(offset as u64, (offset_end - offset) as u64)?;
-        Ok(Arc::new(Block::decode(&block_data[..])))
+        let block_data = verify_checksum(&block_data).ok_or(SstCorruption {
+            sst_id: self.id,
+            section: SstSection::Block(block_idx),
+        })?;
+        Ok(Arc::new(Block::decode(block_data)))
     }
 
     /// Read a block from disk, with block cache.
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.read_block_cached_with_rate_limiter(block_idx, None)
+    }
+
+    /// Read a block from disk, with block cache. Only reads that miss the cache take tokens from
+    /// `rate_limiter`.
+    pub fn read_block_cached_with_rate_limiter(
+        &self,
+        block_idx: usize,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Arc<Block>> {
         if let Some(ref block_cache) = self.block_cache {
             let blk = block_cache
-                .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err(|e| anyhow!("{}", e))?;
+                .try_get_with((self.id, block_idx), || {
+                    self.read_block_with_rate_limiter(block_idx, rate_limiter)
+                })
+                // Keep the original error, e.g. `SstCorruption`, unless it is shared with another
+                // reader of the same block.
+                .map_err(|e| Arc::try_unwrap(e).unwrap_or_else(|e| anyhow!("{:#}", e)))?;
             Ok(blk)
         } else {
-            self.read_block(block_idx)
+            self.read_block_with_rate_limiter(block_idx, rate_limiter)
         }
     }
 
@@ -170,10 +370,56 @@ impl SsTable {
             .saturating_sub(1)
     }
 
+    /// The block meta of all data blocks, in key order.
+    pub fn block_metas(&self) -> &[BlockMeta] {
+        &self.block_metas
+    }
+
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
         self.block_metas.len()
     }
+
+    /// The smallest key in the SST.
+    pub fn first_key(&self) -> &Bytes {
+        &self.first_key
+    }
+
+    /// The largest key in the SST.
+    pub fn last_key(&self) -> &Bytes {
+        &self.last_key
+    }
+
+    /// Size of the SST file, in bytes.
+    pub fn table_size(&self) -> u64 {
+        self.file.size()
+    }
+
+    /// Whether `key` may be in the SST, according to its bloom filter. Always true for an SST
+    /// without a filter.
+    pub fn may_contain_key(&self, key: &[u8]) -> bool {
+        self.filters.may_contain_key(key)
+    }
+
+    /// Whether keys with `prefix`, as extracted by `prefix_extractor`, may be in the SST, according
+    /// to its prefix bloom filter. Always true for an SST without a prefix filter, or with a
+    /// filter built by another extractor.
+    pub fn may_contain_prefix(
+        &self,
+        prefix_extractor: &dyn PrefixExtractor,
+        prefix: &[u8],
+    ) -> bool {
+        self.filters.may_contain_prefix(prefix_extractor, prefix)
+    }
+
+    /// When the SST was written.
+    pub fn creation_time(&self) -> SystemTime {
+        self.creation_time
+    }
+
+    pub fn sst_id(&self) -> usize {
+        self.id
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,88 @@
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes};
+
+/// A bloom filter over the keys of an SST. A key that is not in the filter is definitely not in
+/// the SST. It is encoded as:
+///
+/// ```plaintext
+/// | bits | number of probes (u8) |
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bloom {
+    filter: Bytes,
+    /// Number of bits set for each key.
+    k: u8,
+}
+
+impl Bloom {
+    /// Build a filter from the hashes of all keys, using about `bits_per_key` bits for each key.
+    pub fn build_from_key_hashes(key_hashes: &[u32], bits_per_key: usize) -> Self {
+        // Close to optimal for the false positive rate: bits_per_key * ln(2).
+        let k = (bits_per_key * 69 / 100).clamp(1, 30) as u8;
+        let nbits = (key_hashes.len() * bits_per_key).max(64);
+        let nbytes = (nbits + 7) / 8;
+        let nbits = nbytes * 8;
+        let mut filter = vec![0u8; nbytes];
+        for &h in key_hashes {
+            for bit in probes(h, k, nbits) {
+                filter[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Self {
+            filter: filter.into(),
+            k,
+        }
+    }
+
+    /// Whether the key with hash `h` may be in the filter.
+    pub fn may_contain(&self, h: u32) -> bool {
+        let nbits = self.filter.len() * 8;
+        probes(h, self.k, nbits).all(|bit| self.filter[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_slice(&self.filter);
+        buf.put_u8(self.k);
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let Some((&k, filter)) = buf.split_last() else {
+            bail!("empty bloom filter");
+        };
+        if filter.is_empty() || k == 0 {
+            bail!("invalid bloom filter");
+        }
+        Ok(Self {
+            filter: Bytes::copy_from_slice(filter),
+            k,
+        })
+    }
+}
+
+/// The bits probed for the key with hash `h`, found by double hashing.
+fn probes(h: u32, k: u8, nbits: usize) -> impl Iterator<Item = usize> {
+    let delta = h.rotate_right(17);
+    (0..k as u32).map(move |i| h.wrapping_add(i.wrapping_mul(delta)) as usize % nbits)
+}
+
+/// Hash a key for a bloom filter, with the hash function of LevelDB.
+pub fn key_hash(key: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+    let mut h = SEED ^ (key.len() as u32).wrapping_mul(M);
+    let mut words = key.chunks_exact(4);
+    for word in &mut words {
+        h = h.wrapping_add(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = words.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((*byte as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
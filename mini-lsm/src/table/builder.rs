@@ -4,17 +4,29 @@ use std::sync::Arc;
 use anyhow::Result;
 use bytes::BufMut;
 
+use super::bloom::{key_hash, Bloom};
+use super::filter::{Filters, PrefixExtractor};
+use super::footer::{Footer, SST_FORMAT_VERSION};
 use super::{BlockMeta, FileObject, SsTable};
 use crate::block::BlockBuilder;
 use crate::lsm_storage::BlockCache;
+use crate::rate_limiter::{IoPriority, RateLimiter};
 
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
     builder: BlockBuilder,
     first_key: Vec<u8>,
+    last_key: Vec<u8>,
     data: Vec<u8>,
     pub(super) meta: Vec<BlockMeta>,
     block_size: usize,
+    /// `0` if the SST has no bloom filter.
+    bloom_bits_per_key: usize,
+    key_hashes: Vec<u32>,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// Hashes of the distinct prefixes of the keys, if there is a prefix extractor.
+    prefix_hashes: Vec<u32>,
+    last_prefix: Option<Vec<u8>>,
 }
 
 impl SsTableBuilder {
@@ -24,16 +36,51 @@ impl SsTableBuilder {
             data: Vec::new(),
             meta: Vec::new(),
             first_key: Vec::new(),
+            last_key: Vec::new(),
             block_size,
             builder: BlockBuilder::new(block_size),
+            bloom_bits_per_key: 0,
+            key_hashes: Vec::new(),
+            prefix_extractor: None,
+            prefix_hashes: Vec::new(),
+            last_prefix: None,
         }
     }
 
+    /// Build a bloom filter over the keys, using about `bits_per_key` bits for each key. `0`
+    /// means no filter.
+    pub fn bloom_bits_per_key(mut self, bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bits_per_key;
+        self
+    }
+
+    /// Also build a bloom filter over the prefixes of the keys extracted by `prefix_extractor`,
+    /// with the same number of bits for each distinct prefix as for each key. Like the key bloom
+    /// filter, it is only built if [`SsTableBuilder::bloom_bits_per_key`] is non-zero.
+    pub fn prefix_extractor(mut self, prefix_extractor: Arc<dyn PrefixExtractor>) -> Self {
+        self.prefix_extractor = Some(prefix_extractor);
+        self
+    }
+
     /// Adds a key-value pair to SSTable
     pub fn add(&mut self, key: &[u8], value: &[u8]) {
         if self.first_key.is_empty() {
             self.first_key = key.to_vec();
         }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        if self.bloom_bits_per_key > 0 {
+            self.key_hashes.push(key_hash(key));
+            if let Some(prefix_extractor) = &self.prefix_extractor {
+                // Keys are added in order, so keys with the same prefix are next to each other.
+                if let Some(prefix) = prefix_extractor.prefix(key) {
+                    if self.last_prefix.as_deref() != Some(prefix) {
+                        self.prefix_hashes.push(key_hash(prefix));
+                        self.last_prefix = Some(prefix.to_vec());
+                    }
+                }
+            }
+        }
 
         if self.builder.add(key, value) {
             return;
@@ -58,29 +105,75 @@ impl SsTableBuilder {
             offset: self.data.len(),
             first_key: std::mem::take(&mut self.first_key).into(),
         });
-        self.data.extend(encoded_block);
+        self.data.extend(&encoded_block);
+        self.data.put_u32(crc32fast::hash(&encoded_block));
     }
 
     /// Builds the SSTable and writes it to the given path. No need to actually write to disk until
     /// chapter 4 block cache.
     pub fn build(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        self.build_with_rate_limiter(id, block_cache, path, None, IoPriority::High)
+    }
+
+    /// Like [`SsTableBuilder::build`], but the file is written through `rate_limiter` at
+    /// `priority`.
+    pub fn build_with_rate_limiter(
         mut self,
         id: usize,
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
+        rate_limiter: Option<&RateLimiter>,
+        priority: IoPriority,
     ) -> Result<SsTable> {
         self.finish_block();
         let mut buf = self.data;
         let meta_offset = buf.len();
         BlockMeta::encode_block_meta(&self.meta, &mut buf);
-        buf.put_u32(meta_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
+        let meta_checksum = crc32fast::hash(&buf[meta_offset..]);
+        buf.put_u32(meta_checksum);
+        let filter_offset = buf.len();
+        let mut filters = Filters::default();
+        if self.bloom_bits_per_key > 0 {
+            filters.key_bloom = Some(Bloom::build_from_key_hashes(
+                &self.key_hashes,
+                self.bloom_bits_per_key,
+            ));
+            filters.prefix_bloom = self.prefix_extractor.map(|prefix_extractor| {
+                (
+                    prefix_extractor.name().to_string(),
+                    Bloom::build_from_key_hashes(&self.prefix_hashes, self.bloom_bits_per_key),
+                )
+            });
+        }
+        filters.encode(&mut buf);
+        if buf.len() > filter_offset {
+            let filter_checksum = crc32fast::hash(&buf[filter_offset..]);
+            buf.put_u32(filter_checksum);
+        }
+        let footer = Footer {
+            meta_offset,
+            filter_offset,
+            properties_offset: buf.len(),
+            version: SST_FORMAT_VERSION,
+        };
+        footer.encode(&mut buf);
+        let file =
+            FileObject::create_with_rate_limiter(path.as_ref(), buf, rate_limiter, priority)?;
         Ok(SsTable {
             id,
+            creation_time: file.modified()?,
             file,
+            first_key: self.meta[0].first_key.clone(),
+            last_key: self.last_key.into(),
             block_metas: self.meta,
             block_meta_offset: meta_offset,
             block_cache,
+            filters,
         })
     }
 
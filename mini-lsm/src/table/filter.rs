@@ -0,0 +1,149 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+use super::bloom::{key_hash, Bloom};
+
+/// Extracts a prefix from keys, e.g. the tenant of a key like `tenant/123/...`. SSTs then have a
+/// bloom filter over the prefixes of their keys, so that scans within a prefix skip SSTs without
+/// it.
+///
+/// The prefix of a key must be a prefix of the key, and all keys starting with a prefix must have
+/// the same prefix.
+pub trait PrefixExtractor: Send + Sync + std::fmt::Debug {
+    /// Identifies the extractor. SSTs written with a different extractor are never skipped.
+    fn name(&self) -> &str;
+
+    /// The prefix of `key`, or `None` if the key has no prefix.
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+/// The first `len` bytes of keys. Shorter keys have no prefix.
+#[derive(Debug)]
+pub struct FixedLengthPrefixExtractor {
+    len: usize,
+    name: String,
+}
+
+impl FixedLengthPrefixExtractor {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            name: format!("fixed:{}", len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedLengthPrefixExtractor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.len)
+    }
+}
+
+/// The filters of an SST, stored in its filter section. In format version 2, the section is
+/// empty if there are no filters, or else encoded as:
+///
+/// ```plaintext
+/// | key filter len (u32) | key filter | prefix filter len (u32) | prefix filter |
+/// | extractor name len (u16) | extractor name | checksum (u32) |
+/// ```
+///
+/// where an empty filter is not present. In version 1, the section only holds the key filter,
+/// followed by a checksum.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Filters {
+    /// Bloom filter over all keys.
+    pub(crate) key_bloom: Option<Bloom>,
+    /// Bloom filter over the prefixes of all keys, and the name of the extractor of the prefixes.
+    pub(crate) prefix_bloom: Option<(String, Bloom)>,
+}
+
+const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+impl Filters {
+    /// Encode the filters in the current format version, without the checksum.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        if self.key_bloom.is_none() && self.prefix_bloom.is_none() {
+            return;
+        }
+        for bloom in [
+            self.key_bloom.as_ref(),
+            self.prefix_bloom.as_ref().map(|(_, bloom)| bloom),
+        ] {
+            let mut encoded = Vec::new();
+            if let Some(bloom) = bloom {
+                bloom.encode(&mut encoded);
+            }
+            buf.put_u32(encoded.len() as u32);
+            buf.put_slice(&encoded);
+        }
+        let name = self
+            .prefix_bloom
+            .as_ref()
+            .map_or("", |(name, _)| name.as_str());
+        buf.put_u16(name.len() as u16);
+        buf.put_slice(name.as_bytes());
+    }
+
+    /// Decode the filters of the given format version, without the checksum.
+    pub(crate) fn decode(version: u32, mut buf: &[u8]) -> Result<Self> {
+        if buf.is_empty() {
+            return Ok(Self::default());
+        }
+        if version == 1 {
+            return Ok(Self {
+                key_bloom: Some(Bloom::decode(buf)?),
+                prefix_bloom: None,
+            });
+        }
+        let mut decode_bloom = || {
+            if buf.remaining() < SIZEOF_U32 {
+                bail!("truncated filter");
+            }
+            let len = buf.get_u32() as usize;
+            if buf.remaining() < len {
+                bail!("truncated filter");
+            }
+            let bloom = (len > 0).then(|| Bloom::decode(&buf[..len])).transpose()?;
+            buf.advance(len);
+            Ok(bloom)
+        };
+        let key_bloom = decode_bloom()?;
+        let prefix_bloom = decode_bloom()?;
+        if buf.remaining() < SIZEOF_U16 {
+            bail!("truncated filter");
+        }
+        let name_len = buf.get_u16() as usize;
+        if buf.remaining() != name_len {
+            bail!("truncated filter");
+        }
+        let name = String::from_utf8(buf.to_vec())?;
+        Ok(Self {
+            key_bloom,
+            prefix_bloom: prefix_bloom.map(|bloom| (name, bloom)),
+        })
+    }
+
+    pub(crate) fn may_contain_key(&self, key: &[u8]) -> bool {
+        self.key_bloom
+            .as_ref()
+            .map_or(true, |bloom| bloom.may_contain(key_hash(key)))
+    }
+
+    pub(crate) fn may_contain_prefix(
+        &self,
+        prefix_extractor: &dyn PrefixExtractor,
+        prefix: &[u8],
+    ) -> bool {
+        match &self.prefix_bloom {
+            Some((name, bloom)) if name == prefix_extractor.name() => {
+                bloom.may_contain(key_hash(prefix))
+            }
+            _ => true,
+        }
+    }
+}
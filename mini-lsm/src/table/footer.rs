@@ -0,0 +1,93 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+use super::{FileObject, SIZEOF_U32};
+
+/// Identifies a file as an SST. Stored in the last bytes of the file.
+pub(crate) const SST_MAGIC: u32 = 0x6d6c_736d; // "mlsm"
+
+/// The format version written by this build.
+pub(crate) const SST_FORMAT_VERSION: u32 = 2;
+
+/// The footer at the end of an SST, locating the other sections of the file. Sections are stored
+/// in the order below, and each of them ends where the next one starts. A section that is not
+/// present is empty.
+///
+/// ```plaintext
+/// | data blocks | block meta | filter | properties | footer |
+/// ```
+///
+/// The footer of versions 1 and 2 is encoded as:
+///
+/// ```plaintext
+/// | meta offset (u32) | filter offset (u32) | properties offset (u32) | version (u32) | magic (u32) |
+/// ```
+///
+/// The version and the magic number always come last, so that a reader can tell the size of the
+/// rest of the footer from the version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Footer {
+    pub(crate) meta_offset: usize,
+    pub(crate) filter_offset: usize,
+    pub(crate) properties_offset: usize,
+    /// The format version of the SST. [`Footer::encode`] always writes the current version.
+    pub(crate) version: u32,
+}
+
+impl Footer {
+    /// Size of the encoded footer of the given version.
+    fn encoded_len(version: u32) -> Option<usize> {
+        match version {
+            1 | 2 => Some(SIZEOF_U32 * 5),
+            _ => None,
+        }
+    }
+
+    /// Encode the footer of the current version.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u32(self.meta_offset as u32);
+        buf.put_u32(self.filter_offset as u32);
+        buf.put_u32(self.properties_offset as u32);
+        buf.put_u32(SST_FORMAT_VERSION);
+        buf.put_u32(SST_MAGIC);
+    }
+
+    /// Read and validate the footer of SST `id`.
+    pub(crate) fn read(id: usize, file: &FileObject) -> Result<Self> {
+        let len = file.size() as usize;
+        if len < SIZEOF_U32 * 2 {
+            bail!("SST {} is too small: {} bytes", id, len);
+        }
+        let mut tail = &file.read((len - SIZEOF_U32 * 2) as u64, (SIZEOF_U32 * 2) as u64)?[..];
+        let version = tail.get_u32();
+        let magic = tail.get_u32();
+        if magic != SST_MAGIC {
+            bail!(
+                "SST {} is not an SST file: bad magic number {:#x}",
+                id,
+                magic
+            );
+        }
+        let Some(footer_len) = Self::encoded_len(version) else {
+            bail!("SST {} has unsupported format version {}", id, version);
+        };
+        if len < footer_len {
+            bail!("SST {} is too small: {} bytes", id, len);
+        }
+        let footer_offset = len - footer_len;
+        let mut buf = &file.read(footer_offset as u64, footer_len as u64)?[..];
+        let footer = Self {
+            meta_offset: buf.get_u32() as usize,
+            filter_offset: buf.get_u32() as usize,
+            properties_offset: buf.get_u32() as usize,
+            version,
+        };
+        if footer.meta_offset > footer.filter_offset
+            || footer.filter_offset > footer.properties_offset
+            || footer.properties_offset > footer_offset
+        {
+            bail!("SST {} has invalid section offsets: {:?}", id, footer);
+        }
+        Ok(footer)
+    }
+}
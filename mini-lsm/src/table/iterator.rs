@@ -5,50 +5,76 @@ use anyhow::Result;
 use super::SsTable;
 use crate::block::BlockIterator;
 use crate::iterators::StorageIterator;
+use crate::rate_limiter::RateLimiter;
 
 /// An iterator over the contents of an SSTable.
 pub struct SsTableIterator {
     table: Arc<SsTable>,
     blk_iter: BlockIterator,
     blk_idx: usize,
+    /// Block reads take tokens from the rate limiter, e.g. in compactions.
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl SsTableIterator {
-    fn seek_to_first_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+    fn seek_to_first_inner(
+        table: &Arc<SsTable>,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<(usize, BlockIterator)> {
         Ok((
             0,
-            BlockIterator::create_and_seek_to_first(table.read_block_cached(0)?),
+            BlockIterator::create_and_seek_to_first(
+                table.read_block_cached_with_rate_limiter(0, rate_limiter)?,
+            ),
         ))
     }
 
     /// Create a new iterator and seek to the first key-value pair.
     pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
-        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&table)?;
+        Self::create_and_seek_to_first_with_rate_limiter(table, None)
+    }
+
+    /// Create a new iterator whose block reads take tokens from `rate_limiter`, and seek to the
+    /// first key-value pair.
+    pub fn create_and_seek_to_first_with_rate_limiter(
+        table: Arc<SsTable>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&table, rate_limiter.as_deref())?;
         let iter = Self {
             blk_iter,
             table,
             blk_idx,
+            rate_limiter,
         };
         Ok(iter)
     }
 
     /// Seek to the first key-value pair.
     pub fn seek_to_first(&mut self) -> Result<()> {
-        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&self.table)?;
+        let (blk_idx, blk_iter) =
+            Self::seek_to_first_inner(&self.table, self.rate_limiter.as_deref())?;
         self.blk_idx = blk_idx;
         self.blk_iter = blk_iter;
         Ok(())
     }
 
-    fn seek_to_key_inner(table: &Arc<SsTable>, key: &[u8]) -> Result<(usize, BlockIterator)> {
+    fn seek_to_key_inner(
+        table: &Arc<SsTable>,
+        key: &[u8],
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<(usize, BlockIterator)> {
         let mut blk_idx = table.find_block_idx(key);
-        let mut blk_iter =
-            BlockIterator::create_and_seek_to_key(table.read_block_cached(blk_idx)?, key);
+        let mut blk_iter = BlockIterator::create_and_seek_to_key(
+            table.read_block_cached_with_rate_limiter(blk_idx, rate_limiter)?,
+            key,
+        );
         if !blk_iter.is_valid() {
             blk_idx += 1;
             if blk_idx < table.num_of_blocks() {
-                blk_iter =
-                    BlockIterator::create_and_seek_to_first(table.read_block_cached(blk_idx)?);
+                blk_iter = BlockIterator::create_and_seek_to_first(
+                    table.read_block_cached_with_rate_limiter(blk_idx, rate_limiter)?,
+                );
             }
         }
         Ok((blk_idx, blk_iter))
@@ -56,18 +82,30 @@ impl SsTableIterator {
 
     /// Create a new iterator and seek to the first key-value pair which >= `key`.
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
-        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key)?;
+        Self::create_and_seek_to_key_with_rate_limiter(table, key, None)
+    }
+
+    /// Create a new iterator whose block reads take tokens from `rate_limiter`, and seek to the
+    /// first key-value pair which >= `key`.
+    pub fn create_and_seek_to_key_with_rate_limiter(
+        table: Arc<SsTable>,
+        key: &[u8],
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key, rate_limiter.as_deref())?;
         let iter = Self {
             blk_iter,
             table,
             blk_idx,
+            rate_limiter,
         };
         Ok(iter)
     }
 
     /// Seek to the first key-value pair which >= `key`.
     pub fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
-        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
+        let (blk_idx, blk_iter) =
+            Self::seek_to_key_inner(&self.table, key, self.rate_limiter.as_deref())?;
         self.blk_iter = blk_iter;
         self.blk_idx = blk_idx;
         Ok(())
@@ -93,7 +131,10 @@ impl StorageIterator for SsTableIterator {
             self.blk_idx += 1;
             if self.blk_idx < self.table.num_of_blocks() {
                 self.blk_iter = BlockIterator::create_and_seek_to_first(
-                    self.table.read_block_cached(self.blk_idx)?,
+                    self.table.read_block_cached_with_rate_limiter(
+                        self.blk_idx,
+                        self.rate_limiter.as_deref(),
+                    )?,
                 );
             }
         }
@@ -128,3 +128,241 @@ fn test_sst_seek_key() {
         iter.seek_to_key(b"k").unwrap();
     }
 }
+
+#[test]
+fn test_sst_open_from_disk() {
+    let (dir, sst) = generate_sst();
+    let meta = sst.block_metas.clone();
+    drop(sst);
+    let file = FileObject::open(&dir.path().join("1.sst")).unwrap();
+    let sst = Arc::new(SsTable::open_for_test(file).unwrap());
+    assert_eq!(sst.block_metas, meta);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    for i in 0..num_of_keys() {
+        assert_eq!(iter.key(), key_of(i));
+        assert_eq!(iter.value(), value_of(i));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_open_invalid_file() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    std::fs::write(&path, b"233").unwrap();
+    assert!(SsTable::open_for_test(FileObject::open(&path).unwrap()).is_err());
+    std::fs::write(&path, b"\xff\xff\xff\xff").unwrap();
+    assert!(SsTable::open_for_test(FileObject::open(&path).unwrap()).is_err());
+}
+
+/// Flip a bit of the SST file at `offset`.
+fn corrupt_file(path: &std::path::Path, offset: usize) {
+    let mut data = std::fs::read(path).unwrap();
+    data[offset] ^= 1;
+    std::fs::write(path, data).unwrap();
+}
+
+#[test]
+fn test_sst_corrupted_block() {
+    let (dir, sst) = generate_sst();
+    let path = dir.path().join("1.sst");
+    let corrupted_block = 1;
+    let offset = sst.block_metas[corrupted_block].offset;
+    drop(sst);
+    corrupt_file(&path, offset);
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert!(sst.read_block(0).is_ok());
+    let err = sst.read_block(corrupted_block).err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<SstCorruption>(),
+        Some(&SstCorruption {
+            sst_id: 0,
+            section: SstSection::Block(corrupted_block),
+        })
+    );
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    while iter.is_valid() {
+        if iter.next().is_err() {
+            return;
+        }
+    }
+    panic!("corrupted block is not detected by the iterator");
+}
+
+#[test]
+fn test_sst_corrupted_meta() {
+    let (dir, sst) = generate_sst();
+    let path = dir.path().join("1.sst");
+    let offset = sst.block_meta_offset;
+    drop(sst);
+    corrupt_file(&path, offset);
+    let err = SsTable::open_for_test(FileObject::open(&path).unwrap())
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.downcast_ref::<SstCorruption>(),
+        Some(&SstCorruption {
+            sst_id: 0,
+            section: SstSection::BlockMeta,
+        })
+    );
+}
+
+#[test]
+fn test_sst_footer_version() {
+    let (dir, sst) = generate_sst();
+    let path = dir.path().join("1.sst");
+    drop(sst);
+    let data = std::fs::read(&path).unwrap();
+    // The footer ends with the format version and the magic number.
+    assert_eq!(&data[data.len() - 8..data.len() - 4], b"\x00\x00\x00\x02");
+    assert_eq!(&data[data.len() - 4..], b"mlsm");
+
+    let mut unknown_version = data.clone();
+    let len = unknown_version.len();
+    unknown_version[len - 5] = 0xff;
+    std::fs::write(&path, unknown_version).unwrap();
+    let err = SsTable::open_for_test(FileObject::open(&path).unwrap())
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("unsupported format version"));
+
+    let mut bad_magic = data;
+    let len = bad_magic.len();
+    bad_magic[len - 1] = 0;
+    std::fs::write(&path, bad_magic).unwrap();
+    let err = SsTable::open_for_test(FileObject::open(&path).unwrap())
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("not an SST file"));
+}
+
+#[test]
+fn test_sst_build_no_temp_file() {
+    let (dir, _sst) = generate_sst();
+    let files = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(files, vec!["1.sst"]);
+}
+
+#[test]
+fn test_sst_bloom_filter() {
+    let mut builder = SsTableBuilder::new(128).bloom_bits_per_key(10);
+    for idx in 0..num_of_keys() {
+        builder.add(&key_of(idx), &value_of(idx));
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let sst = builder.build_for_test(&path).unwrap();
+    let reopened = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    for sst in [sst, reopened] {
+        for idx in 0..num_of_keys() {
+            assert!(sst.may_contain_key(&key_of(idx)));
+        }
+        // Keys between the ones in the SST. About 1% of them are false positives.
+        let false_positives = (0..1000)
+            .filter(|i| sst.may_contain_key(format!("key_{:03}_{}", i % 500, i).as_bytes()))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    }
+}
+
+#[test]
+fn test_sst_no_bloom_filter() {
+    let (_dir, sst) = generate_sst();
+    assert!(sst.may_contain_key(b"no_such_key"));
+}
+
+#[test]
+fn test_sst_corrupted_bloom_filter() {
+    let mut builder = SsTableBuilder::new(128).bloom_bits_per_key(10);
+    for idx in 0..num_of_keys() {
+        builder.add(&key_of(idx), &value_of(idx));
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let footer = {
+        let sst = builder.build_for_test(&path).unwrap();
+        Footer::read(0, &sst.file).unwrap()
+    };
+    corrupt_file(&path, footer.filter_offset);
+    let err = SsTable::open_for_test(FileObject::open(&path).unwrap())
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.downcast_ref::<SstCorruption>(),
+        Some(&SstCorruption {
+            sst_id: 0,
+            section: SstSection::Filter,
+        })
+    );
+}
+
+fn tenant_key_of(tenant: usize, idx: usize) -> Vec<u8> {
+    format!("tenant/{:03}/{:03}", tenant, idx).into_bytes()
+}
+
+fn tenant_prefix_of(tenant: usize) -> Vec<u8> {
+    format!("tenant/{:03}/", tenant).into_bytes()
+}
+
+#[test]
+fn test_sst_prefix_bloom_filter() {
+    let prefix_extractor: Arc<dyn PrefixExtractor> = Arc::new(FixedLengthPrefixExtractor::new(11));
+    let mut builder = SsTableBuilder::new(128)
+        .bloom_bits_per_key(10)
+        .prefix_extractor(prefix_extractor.clone());
+    // Even tenants only.
+    for tenant in (0..200).step_by(2) {
+        for idx in 0..5 {
+            builder.add(&tenant_key_of(tenant, idx), &value_of(idx));
+        }
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let sst = builder.build_for_test(&path).unwrap();
+    let reopened = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    for sst in [sst, reopened] {
+        for tenant in (0..200).step_by(2) {
+            assert!(sst.may_contain_prefix(&*prefix_extractor, &tenant_prefix_of(tenant)));
+            assert!(sst.may_contain_key(&tenant_key_of(tenant, 0)));
+        }
+        let false_positives = (1..200)
+            .step_by(2)
+            .filter(|tenant| sst.may_contain_prefix(&*prefix_extractor, &tenant_prefix_of(*tenant)))
+            .count();
+        assert!(false_positives < 10, "{} false positives", false_positives);
+        // The filter says nothing about the prefixes of another extractor.
+        let other_extractor = FixedLengthPrefixExtractor::new(10);
+        for tenant in (1..200).step_by(2) {
+            assert!(sst.may_contain_prefix(&other_extractor, &tenant_prefix_of(tenant)[..10]));
+        }
+    }
+}
+
+#[test]
+fn test_sst_no_prefix_bloom_filter() {
+    let mut builder = SsTableBuilder::new(128).bloom_bits_per_key(10);
+    builder.add(&tenant_key_of(0, 0), &value_of(0));
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let prefix_extractor = FixedLengthPrefixExtractor::new(11);
+    assert!(sst.may_contain_prefix(&prefix_extractor, &tenant_prefix_of(1)));
+}
+
+#[test]
+fn test_sst_decode_filters_v1() {
+    // The filter section of version 1 only holds the key filter.
+    let key_hashes = (0..num_of_keys())
+        .map(|idx| bloom::key_hash(&key_of(idx)))
+        .collect::<Vec<_>>();
+    let bloom = bloom::Bloom::build_from_key_hashes(&key_hashes, 10);
+    let mut buf = Vec::new();
+    bloom.encode(&mut buf);
+    let filters = Filters::decode(1, &buf).unwrap();
+    assert_eq!(filters.key_bloom, Some(bloom));
+    assert!(filters.prefix_bloom.is_none());
+}
@@ -1 +1,16 @@
+use std::path::{Path, PathBuf};
+
 pub mod day4_tests;
+pub mod day5_tests;
+pub mod day6_tests;
+
+/// Paths of the files in `dir` with extension `ext`, e.g. `sst`, sorted by name.
+pub(crate) fn files_with_extension(dir: impl AsRef<Path>, ext: &str) -> Vec<PathBuf> {
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |x| x == ext))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
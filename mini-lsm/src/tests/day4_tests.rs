@@ -3,6 +3,7 @@ use std::ops::Bound;
 use bytes::Bytes;
 use tempfile::tempdir;
 
+use super::files_with_extension;
 use crate::iterators::StorageIterator;
 
 fn as_bytes(x: &[u8]) -> Bytes {
@@ -114,7 +115,7 @@ fn test_storage_get_after_sync() {
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(b"1", b"233").unwrap();
     storage.put(b"2", b"2333").unwrap();
-    storage.sync().unwrap();
+    storage.force_flush().unwrap();
     storage.put(b"3", b"23333").unwrap();
     assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
     assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
@@ -130,7 +131,7 @@ fn test_storage_scan_memtable_1_after_sync() {
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(b"1", b"233").unwrap();
     storage.put(b"2", b"2333").unwrap();
-    storage.sync().unwrap();
+    storage.force_flush().unwrap();
     storage.put(b"3", b"23333").unwrap();
     storage.delete(b"2").unwrap();
     check_iter_result(
@@ -161,9 +162,9 @@ fn test_storage_scan_memtable_2_after_sync() {
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(b"1", b"233").unwrap();
     storage.put(b"2", b"2333").unwrap();
-    storage.sync().unwrap();
+    storage.force_flush().unwrap();
     storage.put(b"3", b"23333").unwrap();
-    storage.sync().unwrap();
+    storage.force_flush().unwrap();
     storage.delete(b"1").unwrap();
     check_iter_result(
         storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
@@ -185,3 +186,176 @@ fn test_storage_scan_memtable_2_after_sync() {
         vec![(Bytes::from("2"), Bytes::from("2333"))],
     );
 }
+
+#[test]
+fn test_storage_freeze_on_size_limit() {
+    use crate::lsm_storage::{LsmStorage, LsmStorageOptions};
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(
+        &dir,
+        LsmStorageOptions {
+            memtable_size_limit: 1024,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    for i in 0..1000 {
+        storage
+            .put(format!("key_{:05}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    for i in (0..1000).step_by(2) {
+        storage.delete(format!("key_{:05}", i).as_bytes()).unwrap();
+    }
+    for i in 0..1000 {
+        let value = storage.get(format!("key_{:05}", i).as_bytes()).unwrap();
+        if i % 2 == 0 {
+            assert!(value.is_none());
+        } else {
+            assert_eq!(&value.unwrap()[..], b"value");
+        }
+    }
+    check_iter_result(
+        storage
+            .scan(Bound::Included(b"key_00000"), Bound::Excluded(b"key_00004"))
+            .unwrap(),
+        vec![
+            (Bytes::from("key_00001"), Bytes::from("value")),
+            (Bytes::from("key_00003"), Bytes::from("value")),
+        ],
+    );
+    storage.force_flush().unwrap();
+    // Frozen memtables are flushed to SSTs, which may have been compacted in the background.
+    assert!(!files_with_extension(&dir, "sst").is_empty());
+    assert_eq!(files_with_extension(&dir, "wal").len(), 1);
+    assert_eq!(
+        &storage
+            .get(format!("key_{:05}", 999).as_bytes())
+            .unwrap()
+            .unwrap()[..],
+        b"value"
+    );
+}
+
+#[test]
+fn test_storage_freeze_concurrent() {
+    use std::sync::Arc;
+
+    use crate::lsm_storage::{LsmStorage, LsmStorageOptions};
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(
+        LsmStorage::open_with_options(
+            &dir,
+            LsmStorageOptions {
+                memtable_size_limit: 512,
+                ..Default::default()
+            },
+        )
+        .unwrap(),
+    );
+    let writers = (0..4)
+        .map(|t| {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    let key = format!("key_{}_{:05}", t, i);
+                    storage.put(key.as_bytes(), b"value").unwrap();
+                    // A key written by this thread must stay visible while memtables are frozen.
+                    assert!(storage.get(key.as_bytes()).unwrap().is_some());
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    for t in 0..4 {
+        for i in 0..500 {
+            let key = format!("key_{}_{:05}", t, i);
+            assert!(storage.get(key.as_bytes()).unwrap().is_some());
+        }
+    }
+}
+
+#[test]
+fn test_storage_scan_prefix_skips_ssts() {
+    use std::sync::Arc;
+
+    use crate::compact::CompactionOptions;
+    use crate::lsm_storage::{LsmStorage, LsmStorageOptions};
+    use crate::table::FixedLengthPrefixExtractor;
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        prefix_extractor: Some(Arc::new(FixedLengthPrefixExtractor::new(11))),
+        ..Default::default()
+    };
+    {
+        let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+        // The key range of the first SST covers tenant 123, but the SST has none of its keys.
+        for i in 0..100 {
+            storage
+                .put(format!("tenant/122/{:03}", i).as_bytes(), b"value")
+                .unwrap();
+            storage
+                .put(format!("tenant/124/{:03}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+        storage.put(b"tenant/123/001", b"value1").unwrap();
+        storage.put(b"tenant/123/002", b"value2").unwrap();
+        storage.force_flush().unwrap();
+    }
+    // Corrupt the first block of the first SST, so that reading it fails.
+    let sst_path = files_with_extension(&dir, "sst").remove(0);
+    let mut data = std::fs::read(&sst_path).unwrap();
+    data[0] ^= 1;
+    std::fs::write(&sst_path, data).unwrap();
+
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let expected = vec![
+        (as_bytes(b"tenant/123/001"), as_bytes(b"value1")),
+        (as_bytes(b"tenant/123/002"), as_bytes(b"value2")),
+    ];
+    // `tenant/1230` is the first key after all keys starting with `tenant/123/`.
+    check_iter_result(
+        storage
+            .scan(
+                Bound::Included(b"tenant/123/"),
+                Bound::Excluded(b"tenant/1230"),
+            )
+            .unwrap(),
+        expected.clone(),
+    );
+    check_iter_result(
+        storage
+            .scan(
+                Bound::Included(b"tenant/123/001"),
+                Bound::Included(b"tenant/123/999"),
+            )
+            .unwrap(),
+        expected,
+    );
+    // A range spanning several prefixes reads the first SST.
+    assert!(storage
+        .scan(
+            Bound::Included(b"tenant/122/"),
+            Bound::Excluded(b"tenant/124/"),
+        )
+        .is_err());
+}
+
+#[test]
+fn test_storage_prefix_extractor_requires_bloom() {
+    use std::sync::Arc;
+
+    use crate::lsm_storage::{LsmStorage, LsmStorageOptions};
+    use crate::table::FixedLengthPrefixExtractor;
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        bloom_bits_per_key: 0,
+        prefix_extractor: Some(Arc::new(FixedLengthPrefixExtractor::new(11))),
+        ..Default::default()
+    };
+    assert!(LsmStorage::open_with_options(&dir, options).is_err());
+}
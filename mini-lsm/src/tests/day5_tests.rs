@@ -0,0 +1,842 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use super::files_with_extension;
+use crate::compact::{
+    CompactionFilter, CompactionFilterDecision, CompactionOptions, CompactionStrategy,
+    CompactionTask, FifoCompactionOptions, LeveledCompactionOptions, TieredCompactionOptions,
+};
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::{LsmStorage, LsmStorageInner, LsmStorageOptions};
+use crate::table::{FileObject, SsTable};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize, round: usize) -> Vec<u8> {
+    format!("value_{:05}_{}", i, round).into_bytes()
+}
+
+fn leveled_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        memtable_size_limit: 4096,
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            base_level_size: 8192,
+            level_size_multiplier: 2,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Overwrite all keys in a few rounds, and delete every third key in the last round.
+fn write_rounds(storage: &LsmStorage, num_keys: usize, rounds: usize) {
+    for round in 0..rounds {
+        for i in 0..num_keys {
+            if round == rounds - 1 && i % 3 == 0 {
+                storage.delete(&key_of(i)).unwrap();
+            } else {
+                storage.put(&key_of(i), &value_of(i, round)).unwrap();
+            }
+        }
+    }
+}
+
+fn check_rounds(storage: &LsmStorage, num_keys: usize, rounds: usize) {
+    for i in 0..num_keys {
+        let value = storage.get(&key_of(i)).unwrap();
+        if i % 3 == 0 {
+            assert!(value.is_none(), "key {} should be deleted", i);
+        } else {
+            assert_eq!(value.as_deref(), Some(&value_of(i, rounds - 1)[..]));
+        }
+    }
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for i in (0..num_keys).filter(|i| i % 3 != 0) {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(i));
+        assert_eq!(iter.value(), value_of(i, rounds - 1));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_storage_leveled_compaction() {
+    let dir = tempdir().unwrap();
+    let options = leveled_options();
+    let (num_keys, rounds) = (1000, 3);
+    let sst_ids = {
+        let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+        write_rounds(&storage, num_keys, rounds);
+        storage.force_flush().unwrap();
+        storage.force_compact().unwrap();
+
+        let (l0, levels) = storage.sst_ids();
+        assert!(l0.len() < 2);
+        assert!(levels.iter().any(|level| !level.is_empty()));
+        check_rounds(&storage, num_keys, rounds);
+        // Compacted SSTs are removed from disk.
+        assert_eq!(
+            files_with_extension(&dir, "sst").len(),
+            l0.len() + levels.iter().map(Vec::len).sum::<usize>()
+        );
+        (l0, levels)
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.sst_ids(), sst_ids);
+    check_rounds(&storage, num_keys, rounds);
+}
+
+#[test]
+fn test_storage_leveled_compaction_scan_range() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(&dir, leveled_options()).unwrap();
+    write_rounds(&storage, 1000, 2);
+    storage.force_flush().unwrap();
+    storage.force_compact().unwrap();
+    // Key 102 is deleted.
+    let mut iter = storage
+        .scan(Bound::Excluded(&key_of(100)), Bound::Included(&key_of(104)))
+        .unwrap();
+    for i in [101, 103, 104] {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(i));
+        assert_eq!(iter.value(), value_of(i, 1));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let iter = storage
+        .scan(Bound::Excluded(&key_of(999)), Bound::Unbounded)
+        .unwrap();
+    assert!(!iter.is_valid());
+    assert_eq!(
+        storage.get(b"key_01000").unwrap(),
+        None::<Bytes>,
+        "key out of range"
+    );
+}
+
+fn tiered_options(tiered: TieredCompactionOptions) -> LsmStorageOptions {
+    LsmStorageOptions {
+        memtable_size_limit: 4096,
+        compaction_options: CompactionOptions::Tiered(tiered),
+        ..Default::default()
+    }
+}
+
+/// Number of sorted runs: each L0 SST, and each non-empty level.
+fn num_of_runs(storage: &LsmStorage) -> usize {
+    let (l0, levels) = storage.sst_ids();
+    l0.len() + levels.iter().filter(|level| !level.is_empty()).count()
+}
+
+#[test]
+fn test_storage_tiered_compaction() {
+    let dir = tempdir().unwrap();
+    let options = tiered_options(TieredCompactionOptions {
+        num_runs_trigger: 3,
+        max_size_amplification_percent: 400,
+        ..Default::default()
+    });
+    let (num_keys, rounds) = (1000, 3);
+    let sst_ids = {
+        let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+        write_rounds(&storage, num_keys, rounds);
+        storage.force_flush().unwrap();
+        storage.force_compact().unwrap();
+        assert!(num_of_runs(&storage) < 3);
+        check_rounds(&storage, num_keys, rounds);
+        storage.sst_ids()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.sst_ids(), sst_ids);
+    check_rounds(&storage, num_keys, rounds);
+}
+
+#[test]
+fn test_storage_tiered_compaction_space_amplification() {
+    let dir = tempdir().unwrap();
+    // Any new data beyond the oldest run triggers a full compaction into the bottom level.
+    let options = tiered_options(TieredCompactionOptions {
+        num_runs_trigger: 2,
+        max_size_amplification_percent: 0,
+        ..Default::default()
+    });
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    write_rounds(&storage, 1000, 3);
+    storage.force_flush().unwrap();
+    storage.force_compact().unwrap();
+    let (l0, levels) = storage.sst_ids();
+    assert!(l0.is_empty());
+    assert!(levels[..levels.len() - 1].iter().all(Vec::is_empty));
+    assert!(!levels[levels.len() - 1].is_empty());
+    check_rounds(&storage, 1000, 3);
+}
+
+#[test]
+fn test_storage_no_compaction() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        memtable_size_limit: 4096,
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    write_rounds(&storage, 1000, 2);
+    storage.force_flush().unwrap();
+    let sst_ids = storage.sst_ids();
+    storage.force_compact().unwrap();
+    assert_eq!(storage.sst_ids(), sst_ids);
+    assert!(sst_ids.1.iter().all(Vec::is_empty));
+    check_rounds(&storage, 1000, 2);
+}
+
+/// Compacts all of L0 into `output_level` once L0 has `l0_trigger` SSTs, while enabled. All of
+/// L0 is waiting for compaction.
+#[derive(Debug)]
+struct CompactL0ToLevel {
+    l0_trigger: usize,
+    output_level: usize,
+    enabled: AtomicBool,
+}
+
+impl CompactL0ToLevel {
+    fn new(l0_trigger: usize, output_level: usize) -> Self {
+        Self {
+            l0_trigger,
+            output_level,
+            enabled: AtomicBool::new(true),
+        }
+    }
+}
+
+impl CompactionStrategy for CompactL0ToLevel {
+    fn generate_compaction_task(&self, snapshot: &LsmStorageInner) -> Option<CompactionTask> {
+        if !self.enabled.load(Ordering::SeqCst) || snapshot.l0_sstables().len() < self.l0_trigger {
+            return None;
+        }
+        let mut inputs = snapshot
+            .l0_sstables()
+            .iter()
+            .rev()
+            .map(|sst| vec![sst.sst_id()])
+            .collect::<Vec<_>>();
+        inputs.push(
+            snapshot.levels()[self.output_level - 1]
+                .iter()
+                .map(|sst| sst.sst_id())
+                .collect(),
+        );
+        Some(CompactionTask {
+            inputs,
+            output_level: self.output_level,
+            delete_inputs: false,
+        })
+    }
+
+    fn pending_compaction_bytes(&self, snapshot: &LsmStorageInner) -> u64 {
+        snapshot
+            .l0_sstables()
+            .iter()
+            .map(|sst| sst.table_size())
+            .sum()
+    }
+}
+
+#[test]
+fn test_storage_custom_compaction_strategy() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        memtable_size_limit: 4096,
+        compaction_options: CompactionOptions::Custom(Arc::new(CompactL0ToLevel::new(3, 3))),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    write_rounds(&storage, 1000, 3);
+    storage.force_flush().unwrap();
+    storage.force_compact().unwrap();
+    let (l0, levels) = storage.sst_ids();
+    assert!(l0.len() < 3);
+    for (idx, level) in levels.iter().enumerate() {
+        assert_eq!(level.is_empty(), idx != 2);
+    }
+    check_rounds(&storage, 1000, 3);
+}
+
+/// Picks a task with an SST that doesn't exist once L0 has an SST.
+#[derive(Debug)]
+struct InvalidStrategy;
+
+impl CompactionStrategy for InvalidStrategy {
+    fn generate_compaction_task(&self, snapshot: &LsmStorageInner) -> Option<CompactionTask> {
+        if snapshot.l0_sstables().is_empty() {
+            return None;
+        }
+        Some(CompactionTask {
+            inputs: vec![vec![233]],
+            output_level: 1,
+            delete_inputs: false,
+        })
+    }
+}
+
+#[test]
+fn test_storage_invalid_compaction_task() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Custom(Arc::new(InvalidStrategy)),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.force_flush().unwrap();
+    assert!(storage.force_compact().is_err());
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+}
+
+#[test]
+fn test_storage_background_error() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Custom(Arc::new(InvalidStrategy)),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.force_flush().unwrap();
+    // Once the background compaction fails, writes fail as well.
+    let mut result = Ok(());
+    for _ in 0..100 {
+        result = storage.put(b"2", b"2333");
+        if result.is_err() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let err = result.err().unwrap();
+    assert!(err.to_string().contains("compaction failed"), "{:#}", err);
+    assert!(storage.close().is_err());
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+}
+
+#[test]
+fn test_storage_stall_on_imm_memtables() {
+    use crate::rate_limiter::RateLimiter;
+    let dir = tempdir().unwrap();
+    // Flushes take a byte per second, i.e. never finish until the limit is lifted.
+    let rate_limiter = Arc::new(RateLimiter::new(1));
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        memtable_size_limit: 1024,
+        max_imm_memtables: 2,
+        rate_limiter: Some(rate_limiter.clone()),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            write_rounds(&storage, 1000, 1);
+            done.store(true, Ordering::SeqCst);
+        });
+        wait_for_stopped_writer(&storage);
+        assert!(!done.load(Ordering::SeqCst));
+        rate_limiter.set_bytes_per_second(0);
+    });
+    check_rounds(&storage, 1000, 1);
+}
+
+/// Wait until a writer is stopped by a write stall.
+fn wait_for_stopped_writer(storage: &LsmStorage) {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while storage.num_stopped_writers() == 0 {
+        assert!(std::time::Instant::now() < deadline, "no writer is stopped");
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_storage_write_stall() {
+    let dir = tempdir().unwrap();
+    let strategy = Arc::new(CompactL0ToLevel::new(1, 1));
+    strategy.enabled.store(false, Ordering::SeqCst);
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Custom(strategy.clone()),
+        level0_slowdown_writes_trigger: 1,
+        level0_stop_writes_trigger: 2,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for i in 0..2 {
+        storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+        storage.force_flush().unwrap();
+    }
+    // L0 has reached the stop trigger, writes wait for compaction to catch up.
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            storage.put(&key_of(2), &value_of(2, 0)).unwrap();
+            done.store(true, Ordering::SeqCst);
+        });
+        wait_for_stopped_writer(&storage);
+        assert!(!done.load(Ordering::SeqCst));
+        strategy.enabled.store(true, Ordering::SeqCst);
+    });
+    assert!(done.load(Ordering::SeqCst));
+    assert!(storage.sst_ids().0.is_empty());
+    for i in 0..3 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap().as_deref(),
+            Some(&value_of(i, 0)[..])
+        );
+    }
+}
+
+#[test]
+fn test_storage_compact_range() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    // Three L0 SSTs with disjoint key ranges.
+    for run in 0..3 {
+        for i in run * 100..run * 100 + 100 {
+            storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    let (l0, _) = storage.sst_ids();
+    assert_eq!(l0.len(), 3);
+    // Not flushed yet.
+    storage.put(&key_of(155), &value_of(155, 1)).unwrap();
+    let check = |storage: &LsmStorage| {
+        for i in 0..300 {
+            let round = if i == 155 { 1 } else { 0 };
+            assert_eq!(
+                storage.get(&key_of(i)).unwrap().as_deref(),
+                Some(&value_of(i, round)[..])
+            );
+        }
+    };
+
+    storage
+        .compact_range(Bound::Excluded(&key_of(300)), Bound::Unbounded)
+        .unwrap();
+    assert_eq!(storage.sst_ids().0, l0);
+
+    storage
+        .compact_range(Bound::Included(&key_of(150)), Bound::Excluded(&key_of(160)))
+        .unwrap();
+    let (new_l0, levels) = storage.sst_ids();
+    // Only the SST holding the range and the flushed memtable are moved to the bottom level.
+    assert_eq!(new_l0, vec![l0[0], l0[2]]);
+    assert!(levels[..5].iter().all(Vec::is_empty));
+    assert_eq!(levels[5].len(), 1);
+    check(&storage);
+
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    let (new_l0, levels) = storage.sst_ids();
+    assert!(new_l0.is_empty());
+    assert!(levels[..5].iter().all(Vec::is_empty));
+    assert_eq!(levels[5].len(), 1);
+    check(&storage);
+}
+
+#[test]
+fn test_storage_drop_tombstones_in_bottommost_level() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Custom(Arc::new(CompactL0ToLevel::new(3, 3))),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for i in 0..100 {
+        storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+    }
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert_eq!(storage.sst_ids().1[5].len(), 1);
+
+    for i in (0..100).filter(|i| i % 3 == 0) {
+        storage.delete(&key_of(i)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    for i in 100..102 {
+        storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+        storage.force_flush().unwrap();
+    }
+    // L6 still has the deleted keys, so the tombstones are kept in L3.
+    storage.force_compact().unwrap();
+    let (l0, levels) = storage.sst_ids();
+    assert!(l0.is_empty());
+    assert_eq!(levels[2].len(), 1);
+    assert_eq!(storage.num_dropped_tombstones(), 0);
+
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert_eq!(storage.num_dropped_tombstones(), 34);
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for i in (0..102).filter(|i| i >= &100 || i % 3 != 0) {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(i));
+        assert_eq!(iter.value(), value_of(i, 0));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+/// Removes expired entries and upgrades old values.
+#[derive(Debug)]
+struct ExpireFilter;
+
+impl CompactionFilter for ExpireFilter {
+    fn filter(&self, _level: usize, _key: &[u8], value: &[u8]) -> CompactionFilterDecision {
+        match value {
+            b"expired" => CompactionFilterDecision::Remove,
+            b"old" => CompactionFilterDecision::ChangeValue(Bytes::from_static(b"new")),
+            _ => CompactionFilterDecision::Keep,
+        }
+    }
+}
+
+#[test]
+fn test_storage_compaction_filter() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Custom(Arc::new(CompactL0ToLevel::new(3, 3))),
+        compaction_filter: Some(Arc::new(ExpireFilter)),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"expired").unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert!(storage.get(b"2").unwrap().is_none());
+
+    // The filter only runs on compaction.
+    storage.put(b"1", b"expired").unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"expired");
+    storage.put(b"3", b"old").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"4", b"2333").unwrap();
+    storage.force_flush().unwrap();
+    // The older value of key 1 in L6 must not show up again after it is removed in L3.
+    storage.force_compact().unwrap();
+    let (l0, levels) = storage.sst_ids();
+    assert!(l0.is_empty());
+    assert_eq!(levels[2].len(), 1);
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"new");
+    assert_eq!(&storage.get(b"4").unwrap().unwrap()[..], b"2333");
+
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert_eq!(storage.num_dropped_tombstones(), 1);
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for (key, value) in [(&b"3"[..], &b"new"[..]), (b"4", b"2333")] {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key);
+        assert_eq!(iter.value(), value);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_storage_trivial_move() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    let sst_ids = {
+        let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+        for i in 0..100 {
+            storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+        }
+        storage.force_flush().unwrap();
+        let (l0, _) = storage.sst_ids();
+        storage
+            .compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        // The SST is moved to L6 as it is.
+        let (_, levels) = storage.sst_ids();
+        assert_eq!(levels[5], l0);
+
+        // A new SST that does not overlap with anything in L6 is moved next to it.
+        for i in 100..200 {
+            storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+        }
+        storage.force_flush().unwrap();
+        let (new_l0, _) = storage.sst_ids();
+        storage
+            .compact_range(Bound::Included(&key_of(100)), Bound::Unbounded)
+            .unwrap();
+        let (_, levels) = storage.sst_ids();
+        assert_eq!(levels[5], vec![l0[0], new_l0[0]]);
+        storage.sst_ids()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.sst_ids(), sst_ids);
+    for i in 0..200 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap().as_deref(),
+            Some(&value_of(i, 0)[..])
+        );
+    }
+}
+
+#[test]
+fn test_storage_subcompactions() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        compaction_options: CompactionOptions::NoCompaction,
+        max_subcompactions: 4,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for round in 0..2 {
+        for i in 0..1000 {
+            if round == 1 && i % 3 == 0 {
+                storage.delete(&key_of(i)).unwrap();
+            } else {
+                storage.put(&key_of(i), &value_of(i, round)).unwrap();
+            }
+        }
+        storage.force_flush().unwrap();
+    }
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    let (l0, levels) = storage.sst_ids();
+    assert!(l0.is_empty());
+    // Each subcompaction writes its own SST.
+    assert_eq!(levels[5].len(), 4);
+    check_rounds(&storage, 1000, 2);
+    assert_eq!(storage.num_dropped_tombstones(), 334);
+}
+
+/// Write `num_ssts` L0 SSTs of 100 keys each, starting from SST `start`.
+fn write_ssts(storage: &LsmStorage, start: usize, num_ssts: usize) {
+    for sst in start..start + num_ssts {
+        for i in sst * 100..sst * 100 + 100 {
+            storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+}
+
+fn check_ssts(storage: &LsmStorage, deleted: std::ops::Range<usize>, kept: std::ops::Range<usize>) {
+    for i in deleted.start * 100..deleted.end * 100 {
+        assert!(storage.get(&key_of(i)).unwrap().is_none());
+    }
+    for i in kept.start * 100..kept.end * 100 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap().as_deref(),
+            Some(&value_of(i, 0)[..])
+        );
+    }
+}
+
+#[test]
+fn test_storage_fifo_compaction_size_limit() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    {
+        let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+        write_ssts(&storage, 0, 5);
+    }
+    let sst_size = files_with_extension(&dir, "sst")
+        .into_iter()
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .max()
+        .unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Fifo(FifoCompactionOptions {
+            max_table_files_size: sst_size * 3 + sst_size / 2,
+            ttl: None,
+        }),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.force_compact().unwrap();
+    let (l0, levels) = storage.sst_ids();
+    assert_eq!(l0.len(), 3);
+    assert!(levels.iter().all(Vec::is_empty));
+    check_ssts(&storage, 0..2, 2..5);
+    assert_eq!(files_with_extension(&dir, "sst").len(), 3);
+}
+
+#[test]
+fn test_storage_fifo_compaction_ttl() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Fifo(FifoCompactionOptions {
+            max_table_files_size: u64::MAX,
+            ttl: Some(std::time::Duration::from_secs(1)),
+        }),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    write_ssts(&storage, 0, 2);
+    // The background compaction drops the SSTs once they are older than the ttl.
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !storage.sst_ids().0.is_empty() {
+        assert!(std::time::Instant::now() < deadline, "SSTs never expired");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    write_ssts(&storage, 2, 1);
+    storage.force_compact().unwrap();
+    assert_eq!(storage.sst_ids().0.len(), 1);
+    check_ssts(&storage, 0..2, 2..3);
+}
+
+#[test]
+fn test_storage_fifo_compaction_rejects_compact_range() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Fifo(FifoCompactionOptions::default()),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    write_ssts(&storage, 0, 2);
+    assert!(storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .is_err());
+    let (l0, levels) = storage.sst_ids();
+    assert_eq!(l0.len(), 2);
+    assert!(levels.iter().all(Vec::is_empty));
+}
+
+fn open_sst(dir: &tempfile::TempDir, id: usize) -> SsTable {
+    let path = dir.path().join(format!("{:05}.sst", id));
+    SsTable::open(id, None, FileObject::open(&path).unwrap()).unwrap()
+}
+
+#[test]
+fn test_storage_compaction_target_sst_size() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        compaction_options: CompactionOptions::NoCompaction,
+        target_sst_size: 1024,
+        ..Default::default()
+    };
+    let l6 = {
+        let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+        // Two SSTs, so that they are merged instead of moved to L6.
+        for range in [0..500, 500..1000] {
+            for i in range {
+                storage.put(&key_of(i), &value_of(i, 0)).unwrap();
+            }
+            storage.force_flush().unwrap();
+        }
+        storage
+            .compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        let (_, levels) = storage.sst_ids();
+        assert!(levels[5].len() >= 10);
+        for id in &levels[5][..levels[5].len() - 1] {
+            assert!(open_sst(&dir, *id).table_size() >= 1024);
+        }
+        levels[5].clone()
+    };
+    let l6_first_keys = l6
+        .iter()
+        .map(|id| open_sst(&dir, *id).first_key().clone())
+        .collect::<Vec<_>>();
+
+    let options = LsmStorageOptions {
+        block_size: 256,
+        compaction_options: CompactionOptions::Custom(Arc::new(CompactL0ToLevel::new(3, 5))),
+        target_sst_size: 4096,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for range in [0..334, 334..667, 667..1000] {
+        for i in range {
+            storage.put(&key_of(i), &value_of(i, 1)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    storage.force_compact().unwrap();
+    let (l0, levels) = storage.sst_ids();
+    assert!(l0.is_empty());
+    assert_eq!(levels[5], l6);
+    let l5 = levels[4]
+        .iter()
+        .map(|id| open_sst(&dir, *id))
+        .collect::<Vec<_>>();
+    assert!(l5.len() >= 2);
+    // L6 has an SST every 1KB, so L5 SSTs are split where L6 SSTs start, once they reach 2KB.
+    for sst in &l5[1..] {
+        assert!(l6_first_keys.contains(sst.first_key()));
+    }
+    for sst in &l5[..l5.len() - 1] {
+        assert!(sst.table_size() >= 2048);
+    }
+    for i in 0..1000 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap().as_deref(),
+            Some(&value_of(i, 1)[..])
+        );
+    }
+}
+
+#[test]
+fn test_storage_rate_limited_compaction() {
+    use crate::rate_limiter::RateLimiter;
+    let dir = tempdir().unwrap();
+    let rate_limiter = Arc::new(RateLimiter::new(0));
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        rate_limiter: Some(rate_limiter.clone()),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    write_rounds(&storage, 1000, 1);
+    storage.force_flush().unwrap();
+    write_rounds(&storage, 1000, 2);
+    storage.force_flush().unwrap();
+    let input_size = files_with_extension(&dir, "sst")
+        .into_iter()
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum::<u64>();
+
+    // Flushes write whole SSTs through the rate limiter.
+    let before = rate_limiter.total_bytes_through();
+    assert_eq!(before, input_size);
+
+    // Compactions read the blocks of the input and write the output through it as well.
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    let output_size = files_with_extension(&dir, "sst")
+        .into_iter()
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum::<u64>();
+    let through = rate_limiter.total_bytes_through() - before;
+    assert!(through > output_size, "{} <= {}", through, output_size);
+    check_rounds(&storage, 1000, 2);
+}
@@ -0,0 +1,468 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use super::files_with_extension;
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::LsmStorage;
+
+fn check_iter_result(iter: impl StorageIterator, expected: Vec<(Bytes, Bytes)>) {
+    let mut iter = iter;
+    for (k, v) in expected {
+        assert!(iter.is_valid());
+        assert_eq!(k, iter.key());
+        assert_eq!(v, iter.value());
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_storage_recover_from_wal() {
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        storage.put(b"2", b"2333").unwrap();
+        storage.put(b"3", b"23333").unwrap();
+        storage.delete(b"2").unwrap();
+        // drop without sync
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert!(storage.get(b"2").unwrap().is_none());
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_recover_multiple_runs() {
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        storage.put(b"2", b"2333").unwrap();
+    }
+    {
+        // Writes of the first run are replayed from the WAL, and new writes are appended to it.
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"2", b"23333").unwrap();
+        storage.delete(b"1").unwrap();
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"23333");
+    storage.force_flush().unwrap();
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"23333");
+}
+
+#[test]
+fn test_storage_reject_oversized_entry() {
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"a", b"1").unwrap();
+        assert!(storage.put(b"b", &vec![b'v'; 70_000]).is_err());
+        assert!(storage.put(&vec![b'k'; 70_000], b"2").is_err());
+        storage.put(b"z", b"3").unwrap();
+    }
+    // The rejected writes leave nothing in the WAL that breaks the replay of later writes.
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("z"), Bytes::from("3")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_reopen_keeps_writing_to_latest_memtable() {
+    let dir = tempdir().unwrap();
+    for i in 0..3 {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage
+            .put(format!("{}", i).as_bytes(), format!("{}", i).as_bytes())
+            .unwrap();
+    }
+    assert_eq!(files_with_extension(&dir, "wal").len(), 1);
+    let storage = LsmStorage::open(&dir).unwrap();
+    for i in 0..3 {
+        assert_eq!(
+            &storage.get(format!("{}", i).as_bytes()).unwrap().unwrap()[..],
+            format!("{}", i).as_bytes()
+        );
+    }
+}
+
+#[test]
+fn test_storage_reopen_sst() {
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        storage.put(b"2", b"2333").unwrap();
+        storage.force_flush().unwrap();
+        storage.put(b"3", b"23333").unwrap();
+        storage.delete(b"2").unwrap();
+        storage.force_flush().unwrap();
+        storage.put(b"4", b"233333").unwrap();
+    }
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![
+                (Bytes::from("1"), Bytes::from("233")),
+                (Bytes::from("3"), Bytes::from("23333")),
+                (Bytes::from("4"), Bytes::from("233333")),
+            ],
+        );
+        // New SSTs must not overwrite the ones written by the previous run.
+        storage.put(b"1", b"2").unwrap();
+        storage.force_flush().unwrap();
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"2");
+    assert!(storage.get(b"2").unwrap().is_none());
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+    assert_eq!(&storage.get(b"4").unwrap().unwrap()[..], b"233333");
+}
+
+#[test]
+fn test_storage_background_flush() {
+    use crate::lsm_storage::LsmStorageOptions;
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        memtable_size_limit: 1024,
+        ..Default::default()
+    };
+    {
+        let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+        for i in 0..1000 {
+            storage
+                .put(format!("key_{:05}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        // Wait for the flush thread to drain all immutable memtables.
+        let num_of_wals = || files_with_extension(&dir, "wal").len();
+        for _ in 0..100 {
+            if num_of_wals() == 1 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert_eq!(num_of_wals(), 1);
+        for i in 0..1000 {
+            assert_eq!(
+                &storage
+                    .get(format!("key_{:05}", i).as_bytes())
+                    .unwrap()
+                    .unwrap()[..],
+                b"value"
+            );
+        }
+    }
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for i in 0..1000 {
+        assert_eq!(
+            &storage
+                .get(format!("key_{:05}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"value"
+        );
+    }
+}
+
+#[test]
+fn test_storage_close() {
+    use crate::lsm_storage::LsmStorageOptions;
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        memtable_size_limit: 1024,
+        ..Default::default()
+    };
+    {
+        let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+        for i in 0..1000 {
+            storage
+                .put(format!("key_{:05}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        storage.close().unwrap();
+    }
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for i in 0..1000 {
+        assert_eq!(
+            &storage
+                .get(format!("key_{:05}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"value"
+        );
+    }
+}
+
+#[test]
+fn test_storage_write_after_close() {
+    use crate::write_batch::WriteBatch;
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        storage.close().unwrap();
+        assert!(storage.put(b"2", b"2333").is_err());
+        assert!(storage.delete(b"1").is_err());
+        let mut batch = WriteBatch::new();
+        batch.put(b"3", b"23333");
+        assert!(storage.write(&batch).is_err());
+        assert!(storage.force_flush().is_err());
+        assert!(storage
+            .compact_range(Bound::Unbounded, Bound::Unbounded)
+            .is_err());
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(Bytes::from("1"), Bytes::from("233"))],
+    );
+}
+
+#[test]
+fn test_storage_write_batch() {
+    use crate::write_batch::WriteBatch;
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"2", b"2333").put(b"3", b"23333").delete(b"1");
+        storage.write(&batch).unwrap();
+        assert!(storage.get(b"1").unwrap().is_none());
+        assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
+        assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_write_batch_atomic() {
+    use crate::lsm_storage::LsmStorageOptions;
+    use crate::write_batch::WriteBatch;
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        memtable_size_limit: 1024,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    // Every batch writes the same value to both keys, so a reader that sees part of a batch
+    // finds them different.
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..1000 {
+                let value = format!("{:05}", i);
+                let mut batch = WriteBatch::new();
+                batch
+                    .put(b"a", value.as_bytes())
+                    .put(b"b", value.as_bytes());
+                storage.write(&batch).unwrap();
+            }
+        });
+        s.spawn(|| {
+            for _ in 0..1000 {
+                let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+                if !iter.is_valid() {
+                    continue;
+                }
+                let a = Bytes::copy_from_slice(iter.value());
+                iter.next().unwrap();
+                assert_eq!(a, iter.value());
+            }
+        });
+    });
+}
+
+#[test]
+fn test_storage_group_commit() {
+    use crate::lsm_storage::WriteOptions;
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let storage = &storage;
+                s.spawn(move || {
+                    // Mix synchronous and asynchronous writes from the same writers.
+                    let options = WriteOptions { sync: t % 2 == 0 };
+                    for i in 0..100 {
+                        storage
+                            .put_with_options(
+                                format!("key_{}_{:03}", t, i).as_bytes(),
+                                b"value",
+                                &options,
+                            )
+                            .unwrap();
+                    }
+                    storage
+                        .delete_with_options(format!("key_{}_{:03}", t, 0).as_bytes(), &options)
+                        .unwrap();
+                });
+            }
+        });
+        // Synchronous writes waiting for the same group commit share an `fsync`.
+        let num_sync_writes = 4 * 101;
+        assert!(storage.num_wal_syncs() < num_sync_writes);
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    for t in 0..8 {
+        assert!(storage
+            .get(format!("key_{}_{:03}", t, 0).as_bytes())
+            .unwrap()
+            .is_none());
+        for i in 1..100 {
+            assert_eq!(
+                &storage
+                    .get(format!("key_{}_{:03}", t, i).as_bytes())
+                    .unwrap()
+                    .unwrap()[..],
+                b"value"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_storage_remove_temp_files() {
+    let dir = tempdir().unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        storage.force_flush().unwrap();
+    }
+    // A crash in the middle of writing an SST leaves its temporary file behind.
+    let tmp_path = dir.path().join("00100.sst.tmp");
+    std::fs::write(&tmp_path, b"233").unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(!tmp_path.exists());
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+}
+
+#[test]
+fn test_storage_remove_unreferenced_files() {
+    let dir = tempdir().unwrap();
+    // A crash right after creating the WAL of the first memtable, before the manifest exists.
+    std::fs::write(dir.path().join("00001.wal"), b"").unwrap();
+    {
+        let storage = LsmStorage::open(&dir).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        storage.force_flush().unwrap();
+    }
+    // Files of an unfinished memtable and compaction, with ids after the last recorded one.
+    let leftover_wal = dir.path().join("00005.wal");
+    let leftover_sst = dir.path().join("00004.sst");
+    std::fs::write(&leftover_wal, b"").unwrap();
+    std::fs::write(&leftover_sst, b"233").unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(!leftover_wal.exists());
+    assert!(!leftover_sst.exists());
+    for i in 0..3 {
+        storage.put(b"2", format!("{}", i).as_bytes()).unwrap();
+        storage.force_flush().unwrap();
+    }
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2");
+    // The ids of the leftover files are not reused.
+    let (l0, _) = storage.sst_ids();
+    assert_eq!(l0.len(), 4);
+    assert!(!l0.contains(&4) && !l0.contains(&5));
+}
+
+#[test]
+fn test_storage_write_succeeds_when_freeze_fails() {
+    use crate::lsm_storage::LsmStorageOptions;
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        memtable_size_limit: 1024,
+        ..Default::default()
+    };
+    let blockers = (2..10)
+        .map(|id| dir.path().join(format!("{:05}.wal", id)))
+        .collect::<Vec<_>>();
+    let mut num_written = 0;
+    {
+        let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+        // The WALs of the next memtables cannot be created, so freezing fails.
+        for blocker in &blockers {
+            std::fs::create_dir(blocker).unwrap();
+        }
+        // The write that fails to freeze the memtable is still committed. Later writes fail with
+        // the error instead of retrying the freeze.
+        while storage
+            .put(format!("key_{:03}", num_written).as_bytes(), b"value")
+            .is_ok()
+        {
+            num_written += 1;
+            assert!(num_written < 1000);
+        }
+        let err = storage.put(b"key_999", b"value").unwrap_err();
+        assert!(format!("{:#}", err).contains("failed to freeze memtable"));
+        assert!(storage.close().is_err());
+        assert_eq!(storage.sst_ids().0.len(), 0);
+    }
+    for blocker in &blockers {
+        std::fs::remove_dir(blocker).unwrap();
+    }
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for i in 0..num_written {
+        assert_eq!(
+            &storage
+                .get(format!("key_{:03}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"value"
+        );
+    }
+    assert!(storage.get(b"key_999").unwrap().is_none());
+    storage.put(b"key_999", b"value").unwrap();
+    storage.force_flush().unwrap();
+}
+
+#[test]
+fn test_storage_group_commit_sync() {
+    use crate::write_batch::WriteBatch;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let batch = |key: &[u8]| {
+        let mut batch = WriteBatch::new();
+        batch.put(key, b"value");
+        batch
+    };
+    storage
+        .commit_group(vec![(batch(b"1"), false), (batch(b"2"), false)])
+        .unwrap();
+    assert_eq!(storage.num_wal_syncs(), 0);
+    // An asynchronous write grouped with a synchronous one is persisted by the same `fsync`,
+    // which comes after the whole group is logged.
+    storage
+        .commit_group(vec![(batch(b"3"), false), (batch(b"4"), true)])
+        .unwrap();
+    assert_eq!(storage.num_wal_syncs(), 1);
+}
@@ -0,0 +1,218 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::Mutex;
+
+use crate::table::sync_parent_dir;
+
+const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// Maximum length of a key or a value in bytes, as their lengths are encoded as `u16`.
+pub const MAX_KEY_VALUE_LEN: usize = u16::MAX as usize;
+
+/// A batch of key-value pairs, logged as a single WAL record.
+pub type WalBatch = Vec<(Bytes, Bytes)>;
+
+/// A write-ahead log. Every batch written to a memtable is appended to its WAL before it is
+/// applied, so that the memtable can be rebuilt if the process crashes before the memtable is
+/// flushed.
+///
+/// Each batch is a single record, so that it is either replayed as a whole or not at all:
+///
+/// ```plaintext
+/// | body_len (u32) | body | checksum (u32) |
+/// ```
+///
+/// where the body is a list of entries:
+///
+/// ```plaintext
+/// | key_len (u16) | key | value_len (u16) | value | ... |
+/// ```
+pub struct Wal {
+    file: Mutex<WalFile>,
+    /// Number of calls to [`Wal::sync`].
+    #[cfg(test)]
+    num_syncs: std::sync::atomic::AtomicUsize,
+}
+
+struct WalFile {
+    file: File,
+    /// Length of the log up to the end of the last record written in full.
+    len: u64,
+    /// Set if a failed append could not be undone. The log then ends with a partial record, and
+    /// records appended after it would be lost on recovery, so all further appends are refused.
+    failed: bool,
+}
+
+impl Wal {
+    /// Create a new WAL file. Fails if the file already exists. The directory is synced, so that
+    /// the file itself survives a crash.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to create WAL {}", path.as_ref().display()))?;
+        sync_parent_dir(path.as_ref())?;
+        Ok(Self {
+            file: Mutex::new(WalFile {
+                file,
+                len: 0,
+                failed: false,
+            }),
+            #[cfg(test)]
+            num_syncs: Default::default(),
+        })
+    }
+
+    /// Open an existing WAL for appending and return all batches in it. A torn or corrupted record
+    /// at the end of the log (e.g. the process crashed in the middle of a write) is discarded
+    /// together with everything after it.
+    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<WalBatch>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to recover WAL {}", path.as_ref().display()))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut rbuf = &buf[..];
+        let mut batches = Vec::new();
+        while let Some(batch) = Self::decode_record(&mut rbuf) {
+            batches.push(batch);
+        }
+        let valid_len = buf.len() - rbuf.len();
+        if valid_len != buf.len() {
+            // Drop the broken tail so that new records are appended right after the last valid
+            // one.
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        Ok((
+            Self {
+                file: Mutex::new(WalFile {
+                    file,
+                    len: valid_len as u64,
+                    failed: false,
+                }),
+                #[cfg(test)]
+                num_syncs: Default::default(),
+            },
+            batches,
+        ))
+    }
+
+    /// Decode one record from `buf`, advancing it past the record. Returns `None` and leaves `buf`
+    /// untouched if the remaining data is not a complete, valid record.
+    fn decode_record(buf: &mut &[u8]) -> Option<WalBatch> {
+        if buf.remaining() < SIZEOF_U32 {
+            return None;
+        }
+        let body_len = (&buf[..SIZEOF_U32]).get_u32() as usize;
+        if buf.remaining() < SIZEOF_U32 * 2 + body_len {
+            return None;
+        }
+        let mut body = &buf[SIZEOF_U32..SIZEOF_U32 + body_len];
+        let checksum = (&buf[SIZEOF_U32 + body_len..]).get_u32();
+        if checksum != crc32fast::hash(body) {
+            return None;
+        }
+        let mut batch = Vec::new();
+        while body.has_remaining() {
+            let mut decode_slice = || {
+                if body.remaining() < SIZEOF_U16 {
+                    return None;
+                }
+                let len = body.get_u16() as usize;
+                if body.remaining() < len {
+                    return None;
+                }
+                let slice = Bytes::copy_from_slice(&body[..len]);
+                body.advance(len);
+                Some(slice)
+            };
+            let key = decode_slice()?;
+            let value = decode_slice()?;
+            batch.push((key, value));
+        }
+        buf.advance(SIZEOF_U32 * 2 + body_len);
+        Some(batch)
+    }
+
+    /// Append a batch of key-value pairs to the log as a single record. The record is handed to
+    /// the OS in a single write, so it survives a process crash, but it is only durable on disk
+    /// after [`Wal::sync`].
+    pub fn put_batch(&self, batch: &[(Bytes, Bytes)]) -> Result<()> {
+        self.put_batches(&[batch])
+    }
+
+    /// Append multiple batches to the log, one record per batch, in a single write. If the write
+    /// fails, the partially written data is truncated, so that later records are not lost behind
+    /// it on recovery. Nothing is written if a key or a value is longer than
+    /// [`MAX_KEY_VALUE_LEN`].
+    pub fn put_batches(&self, batches: &[&[(Bytes, Bytes)]]) -> Result<()> {
+        let mut buf = Vec::new();
+        for batch in batches {
+            if let Some((key, value)) = batch.iter().find(|(key, value)| {
+                key.len() > MAX_KEY_VALUE_LEN || value.len() > MAX_KEY_VALUE_LEN
+            }) {
+                bail!(
+                    "key of {} bytes or value of {} bytes exceeds the maximum of {} bytes",
+                    key.len(),
+                    value.len(),
+                    MAX_KEY_VALUE_LEN
+                );
+            }
+            let body_len: usize = batch
+                .iter()
+                .map(|(key, value)| key.len() + value.len() + SIZEOF_U16 * 2)
+                .sum();
+            buf.reserve(body_len + SIZEOF_U32 * 2);
+            buf.put_u32(body_len as u32);
+            let body_start = buf.len();
+            for (key, value) in batch.iter() {
+                buf.put_u16(key.len() as u16);
+                buf.put_slice(key);
+                buf.put_u16(value.len() as u16);
+                buf.put_slice(value);
+            }
+            let checksum = crc32fast::hash(&buf[body_start..]);
+            buf.put_u32(checksum);
+        }
+        let mut wal_file = self.file.lock();
+        if wal_file.failed {
+            bail!("WAL is unusable after a failed write");
+        }
+        if let Err(e) = wal_file.file.write_all(&buf) {
+            let len = wal_file.len;
+            if wal_file.file.set_len(len).is_err() {
+                wal_file.failed = true;
+            }
+            return Err(e.into());
+        }
+        wal_file.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Flush the log to disk with `fsync`.
+    pub fn sync(&self) -> Result<()> {
+        self.file.lock().file.sync_all()?;
+        #[cfg(test)]
+        self.num_syncs
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn num_syncs(&self) -> usize {
+        self.num_syncs.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests;
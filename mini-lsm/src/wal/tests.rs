@@ -0,0 +1,103 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use super::{Wal, MAX_KEY_VALUE_LEN};
+
+fn entry(key: &'static str, value: &'static str) -> (Bytes, Bytes) {
+    (Bytes::from(key), Bytes::from(value))
+}
+
+#[test]
+fn test_wal_recover() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.wal");
+    {
+        let wal = Wal::create(&path).unwrap();
+        wal.put_batch(&[entry("key1", "value1")]).unwrap();
+        wal.put_batch(&[entry("key2", "value2"), entry("key1", "value11")])
+            .unwrap();
+        wal.put_batch(&[entry("key3", "")]).unwrap();
+        wal.sync().unwrap();
+    }
+    let (_, batches) = Wal::recover(&path).unwrap();
+    assert_eq!(
+        batches,
+        vec![
+            vec![entry("key1", "value1")],
+            vec![entry("key2", "value2"), entry("key1", "value11")],
+            vec![entry("key3", "")],
+        ]
+    );
+}
+
+#[test]
+fn test_wal_recover_torn_tail() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.wal");
+    {
+        let wal = Wal::create(&path).unwrap();
+        wal.put_batch(&[entry("key1", "value1")]).unwrap();
+        wal.put_batch(&[entry("key2", "value2"), entry("key3", "value3")])
+            .unwrap();
+    }
+    // Simulate a crash in the middle of appending a batch. None of its entries are replayed.
+    let len = std::fs::metadata(&path).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+    {
+        let (wal, batches) = Wal::recover(&path).unwrap();
+        assert_eq!(batches, vec![vec![entry("key1", "value1")]]);
+        // New records go right after the last valid one.
+        wal.put_batch(&[entry("key4", "value4")]).unwrap();
+    }
+    let (_, batches) = Wal::recover(&path).unwrap();
+    assert_eq!(
+        batches,
+        vec![vec![entry("key1", "value1")], vec![entry("key4", "value4")]]
+    );
+}
+
+#[test]
+fn test_wal_recover_corrupted_record() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.wal");
+    {
+        let wal = Wal::create(&path).unwrap();
+        wal.put_batch(&[entry("key1", "value1")]).unwrap();
+    }
+    OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"\x00\x00\x00\x0e\x00\x04key2\x00\x06value2\x00\x00\x00\x00")
+        .unwrap();
+    let (_, batches) = Wal::recover(&path).unwrap();
+    assert_eq!(batches, vec![vec![entry("key1", "value1")]]);
+}
+
+#[test]
+fn test_wal_reject_oversized_entry() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.wal");
+    {
+        let wal = Wal::create(&path).unwrap();
+        wal.put_batch(&[entry("key1", "value1")]).unwrap();
+        let value = Bytes::from(vec![b'v'; MAX_KEY_VALUE_LEN + 1]);
+        assert!(wal
+            .put_batches(&[&[entry("key2", "value2")], &[(Bytes::from("key3"), value)]])
+            .is_err());
+        wal.put_batch(&[entry("key4", "value4")]).unwrap();
+    }
+    let (_, batches) = Wal::recover(&path).unwrap();
+    assert_eq!(
+        batches,
+        vec![vec![entry("key1", "value1")], vec![entry("key4", "value4")]]
+    );
+}
@@ -0,0 +1,47 @@
+use bytes::Bytes;
+
+/// A batch of writes that are applied to the storage atomically with [`LsmStorage::write`]. Either
+/// all of them are visible to readers and replayed after a crash, or none of them is.
+///
+/// [`LsmStorage::write`]: crate::lsm_storage::LsmStorage::write
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    /// Writes in the order they are added. A delete is a write with an empty value.
+    entries: Vec<(Bytes, Bytes)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put a key-value pair into the batch.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        assert!(!value.is_empty(), "value cannot be empty");
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.entries
+            .push((Bytes::copy_from_slice(key), Bytes::copy_from_slice(value)));
+        self
+    }
+
+    /// Remove a key as part of the batch.
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.entries
+            .push((Bytes::copy_from_slice(key), Bytes::new()));
+        self
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn entries(&self) -> &[(Bytes, Bytes)] {
+        &self.entries
+    }
+}